@@ -0,0 +1,200 @@
+/// What the scheduler needs from a light, so it doesn't have to care what
+/// the light speaks (or, in tests, whether there's a light at all).
+use crate::types::*;
+use crate::util::{
+    led_restore_state, led_set_brightness, led_set_nightlight, led_set_playlist, led_set_power,
+    led_set_preset, led_set_primary_seg, led_set_segment_states,
+};
+use anyhow::{anyhow, Result};
+use wled_json_api_library::structures::info::Info;
+use wled_json_api_library::structures::state::State;
+
+pub trait LightBackend {
+    /// Fetches the light's WLED info (model, LED count and so on), for
+    /// groups that match on it. Lights with no such info do nothing here.
+    fn discover(&mut self) -> Result<()>;
+    /// What the light said it is, once `discover` has worked.
+    fn info(&self) -> Option<&Info>;
+    /// The light's current state.
+    fn read_state(&mut self) -> Result<State>;
+    fn set_power(&mut self, power: bool) -> Result<()>;
+    /// Sets the brightness (0-255, where 0 is off), fading over `tt` tenths
+    /// of a second.
+    fn set_brightness(&mut self, bri: u8, tt: u8) -> Result<()>;
+    fn set_preset(&mut self, preset: u16, tt: u8) -> Result<()>;
+    fn set_playlist(&mut self, playlist: PlaylistState) -> Result<()>;
+    fn set_nightlight(&mut self, nightlight: &NightlightState) -> Result<()>;
+    /// Sends the color, effect and so on from `state` to the whole light.
+    fn set_color(&mut self, state: &LEDState, tt: u8) -> Result<()>;
+    /// Sends per-segment states, as from `calc_segments_scheduled`.
+    fn set_segments(&mut self, segments: &[(u8, u8, LEDState)], tt: u8) -> Result<()>;
+    /// Puts the light back to a state read earlier.
+    fn restore_state(&mut self, snapshot: &State) -> Result<()>;
+}
+
+impl LightBackend for WLED {
+    fn discover(&mut self) -> Result<()> {
+        self.device
+            .get_info_from_wled()
+            .map_err(|err| anyhow!("Failed to get info from {}: {:?}", &self.name, err))
+    }
+
+    fn info(&self) -> Option<&Info> {
+        self.device.info.as_ref()
+    }
+
+    /// With a live connection that's the state the WLED last pushed;
+    /// otherwise we ask for it.
+    fn read_state(&mut self) -> Result<State> {
+        if !self.live.as_ref().is_some_and(|live| live.is_connected()) {
+            self.device
+                .get_state_from_wled()
+                .map_err(|err| anyhow!("Failed to get state from {}: {:?}", &self.name, err))?;
+            self.state = self.device.state.clone();
+        }
+        self.state
+            .clone()
+            .ok_or_else(|| anyhow!("No state from {}", &self.name))
+    }
+
+    fn set_power(&mut self, power: bool) -> Result<()> {
+        led_set_power(self, power)
+    }
+
+    fn set_brightness(&mut self, bri: u8, tt: u8) -> Result<()> {
+        led_set_brightness(self, bri, tt)
+    }
+
+    fn set_preset(&mut self, preset: u16, tt: u8) -> Result<()> {
+        led_set_preset(self, preset, tt)
+    }
+
+    fn set_playlist(&mut self, playlist: PlaylistState) -> Result<()> {
+        led_set_playlist(self, playlist)
+    }
+
+    fn set_nightlight(&mut self, nightlight: &NightlightState) -> Result<()> {
+        led_set_nightlight(self, nightlight)
+    }
+
+    fn set_color(&mut self, state: &LEDState, tt: u8) -> Result<()> {
+        led_set_primary_seg(self, state, tt)
+    }
+
+    fn set_segments(&mut self, segments: &[(u8, u8, LEDState)], tt: u8) -> Result<()> {
+        led_set_segment_states(self, segments, tt)
+    }
+
+    fn restore_state(&mut self, snapshot: &State) -> Result<()> {
+        led_restore_state(self, snapshot)
+    }
+}
+
+/// An in-memory light for tests. It keeps a WLED-style state up to date
+/// with what it's sent, and records every command in order.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum MockCommand {
+        Power(bool),
+        Brightness(u8),
+        Preset(u16),
+        Playlist(PlaylistState),
+        Nightlight(NightlightState),
+        Color(LEDState),
+        Segments(Vec<(u8, u8, LEDState)>),
+        Restore(State),
+    }
+
+    #[derive(Debug, Default)]
+    pub struct MockLight {
+        /// What `discover` finds.
+        pub info: Option<Info>,
+        pub discovered: bool,
+        pub state: State,
+        pub commands: Vec<MockCommand>,
+        /// Makes every call fail, as if the light were unplugged.
+        pub offline: bool,
+    }
+
+    impl MockLight {
+        fn send(&mut self, command: MockCommand) -> Result<()> {
+            if self.offline {
+                return Err(anyhow!("Mock light is offline"));
+            }
+            match &command {
+                MockCommand::Power(power) => self.state.on = Some(*power),
+                MockCommand::Brightness(bri) => {
+                    self.state.on = Some(*bri > 0);
+                    self.state.bri = Some(*bri);
+                }
+                MockCommand::Preset(preset) => self.state.ps = Some(*preset as i32),
+                MockCommand::Nightlight(_) => self.state.on = Some(true),
+                MockCommand::Restore(snapshot) => self.state = snapshot.clone(),
+                _ => (),
+            }
+            self.commands.push(command);
+            Ok(())
+        }
+
+        /// The commands sent since the last call.
+        pub fn take_commands(&mut self) -> Vec<MockCommand> {
+            std::mem::take(&mut self.commands)
+        }
+    }
+
+    impl LightBackend for MockLight {
+        fn discover(&mut self) -> Result<()> {
+            if self.offline {
+                return Err(anyhow!("Mock light is offline"));
+            }
+            self.discovered = true;
+            Ok(())
+        }
+
+        fn info(&self) -> Option<&Info> {
+            self.info.as_ref().filter(|_| self.discovered)
+        }
+
+        fn read_state(&mut self) -> Result<State> {
+            if self.offline {
+                return Err(anyhow!("Mock light is offline"));
+            }
+            Ok(self.state.clone())
+        }
+
+        fn set_power(&mut self, power: bool) -> Result<()> {
+            self.send(MockCommand::Power(power))
+        }
+
+        fn set_brightness(&mut self, bri: u8, _tt: u8) -> Result<()> {
+            self.send(MockCommand::Brightness(bri))
+        }
+
+        fn set_preset(&mut self, preset: u16, _tt: u8) -> Result<()> {
+            self.send(MockCommand::Preset(preset))
+        }
+
+        fn set_playlist(&mut self, playlist: PlaylistState) -> Result<()> {
+            self.send(MockCommand::Playlist(playlist))
+        }
+
+        fn set_nightlight(&mut self, nightlight: &NightlightState) -> Result<()> {
+            self.send(MockCommand::Nightlight(*nightlight))
+        }
+
+        fn set_color(&mut self, state: &LEDState, _tt: u8) -> Result<()> {
+            self.send(MockCommand::Color(state.clone()))
+        }
+
+        fn set_segments(&mut self, segments: &[(u8, u8, LEDState)], _tt: u8) -> Result<()> {
+            self.send(MockCommand::Segments(segments.to_vec()))
+        }
+
+        fn restore_state(&mut self, snapshot: &State) -> Result<()> {
+            self.send(MockCommand::Restore(snapshot.clone()))
+        }
+    }
+}
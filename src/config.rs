@@ -1,5 +1,6 @@
 use crate::types::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::Datelike;
 /// Manages the configuration; related tools.
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -28,9 +29,11 @@ fn bootstrap() -> Result<PathBuf> {
         let tmpconfig = Config {
             lat: 49.0,
             lon: -124.0,
+            timezone: None,
             // exclusions: Vec::new(),
             // brightnesses: HashMap::new(),
             leds: HashMap::new(),
+            groups: HashMap::new(),
             // transition_duration: 3600i64,
             loglevel: 4,
             logfile: None,
@@ -38,20 +41,31 @@ fn bootstrap() -> Result<PathBuf> {
             ledfx_url: None,
             ledfx_idle_cycles: Some(3),
             cycle_seconds: 10.0,
+            http_connect_timeout_ms: 1000,
+            http_timeout_ms: 3000,
+            max_parallel_updates: 8,
             schedule: HashMap::from([(
                 "default".to_string(),
                 vec![
                     WLEDScheduleItem {
                         time: ScheduleTime::Sunrise,
                         change: WLEDChange::Brightness(1.0),
+                        ..Default::default()
                     },
                     WLEDScheduleItem {
                         time: ScheduleTime::Sunset,
                         change: WLEDChange::Brightness(0.2),
+                        ..Default::default()
                     },
                 ],
             )]),
             restart_on_cfg_change: CfgChangeAction::No,
+            devices: HashMap::new(),
+            tasmota: HashMap::new(),
+            tasmota_probe: Vec::new(),
+            prefer_address: AddressFamily::V4,
+            live_state: false,
+            mqtt: None,
             tray_icon: false,
             bind_address: Some("localhost:3178".to_string()),
             vis_schedule: None,
@@ -83,16 +97,574 @@ pub fn load_config(cfg_path: Option<PathBuf>) -> Result<Config> {
         None => bootstrap()?,
     };
     let cfgfile = std::fs::read_to_string(&cfgdir)?;
-    let mut cfg: Config = ron::de::from_bytes(cfgfile.as_bytes())?;
+    let mut cfg: Config = ron::de::from_str(&cfgfile).map_err(|err| {
+        anyhow!(
+            "{}:{}:{}: {}",
+            cfgdir.display(),
+            err.position.line,
+            err.position.col,
+            err.code
+        )
+    })?;
+    let problems = validate_config(&cfg);
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "{} has {} problem(s):\n  {}",
+            cfgdir.display(),
+            problems.len(),
+            problems.join("\n  ")
+        ));
+    }
     cfg.config_path = Some(cfgdir.clone());
     Ok(cfg)
 }
 
+/// Looks for the mistakes that RON can't catch, and which would otherwise
+/// only show up at runtime as LEDs quietly going dark. Returns one message
+/// per problem found, so an empty list means the config is good to go.
+pub fn validate_config(cfg: &Config) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    // Checked here, as `Config::zone` would only warn and carry on in UTC.
+    if let Some(timezone) = &cfg.timezone {
+        if let Err(err) = timezone.parse::<chrono_tz::Tz>() {
+            problems.push(format!("Invalid timezone '{}': {}", timezone, err));
+        }
+    }
+
+    let mut led_names: Vec<&String> = cfg.leds.keys().collect();
+    led_names.sort();
+    for name in led_names {
+        problems.extend(validate_led(
+            cfg,
+            &format!("LED '{}'", name),
+            &cfg.leds[name],
+        ));
+    }
+
+    let mut group_names: Vec<&String> = cfg.groups.keys().collect();
+    group_names.sort();
+    for name in group_names {
+        let group = &cfg.groups[name];
+        problems.extend(validate_led(
+            cfg,
+            &format!("Group '{}'", name),
+            &group.config,
+        ));
+    }
+
+    for spec in &cfg.tasmota_probe {
+        if let Err(err) = crate::tasmota::probe_hosts(spec) {
+            problems.push(format!("tasmota_probe: {}", err));
+        }
+    }
+    if let Some(mqtt) = &cfg.mqtt {
+        if mqtt.topic_prefix.is_empty() || mqtt.topic_prefix.contains(['+', '#']) {
+            problems.push(format!(
+                "mqtt: topic_prefix '{}' must be set, with no '+' or '#' in it.",
+                mqtt.topic_prefix
+            ));
+        }
+    }
+
+    let mut schedule_names: Vec<&String> = cfg.schedule.keys().collect();
+    schedule_names.sort();
+    for name in schedule_names {
+        problems.extend(
+            validate_schedule(cfg, &cfg.schedule[name])
+                .into_iter()
+                .map(|problem| format!("Schedule '{}': {}", name, problem)),
+        );
+    }
+    problems
+}
+
+/// Looks for things that are probably mistakes, but which don't stop the
+/// rest of the config working, so they're logged rather than refused.
+pub fn config_warnings(cfg: &Config) -> Vec<String> {
+    let mut warnings: Vec<String> = Vec::new();
+    let mut device_names: Vec<&String> = cfg.devices.keys().collect();
+    device_names.sort();
+    for name in device_names {
+        if cfg.led_config(name, None).is_none() && !cfg.has_info_groups() {
+            warnings.push(format!(
+                "Device '{}' isn't in leds or any group, so it would never be scheduled.",
+                name
+            ));
+        }
+    }
+
+    let mut tasmota_names: Vec<&String> = cfg.tasmota.keys().collect();
+    tasmota_names.sort();
+    for name in tasmota_names {
+        // Tasmota devices have no WLED info, so Info groups never match them.
+        if cfg.led_config(name, None).is_none() {
+            warnings.push(format!(
+                "Tasmota '{}' isn't in leds or any group, so it would never be scheduled.",
+                name
+            ));
+        }
+    }
+    warnings
+}
+
+/// Checks one LED's (or group's) config. `label` says which, e.g. "LED 'x'".
+fn validate_led(cfg: &Config, label: &str, led_config: &LEDBrightnessConfig) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    if let LEDScheduleSpec::ByName(schedule_name) = &led_config.schedule {
+        if !cfg.schedule.contains_key(schedule_name) {
+            problems.push(format!(
+                "{} refers to schedule '{}', which doesn't exist.",
+                label, schedule_name
+            ));
+        }
+    }
+    if led_config.min_bri > led_config.max_bri {
+        problems.push(format!(
+            "{} has min_bri ({}) greater than max_bri ({}).",
+            label, led_config.min_bri, led_config.max_bri
+        ));
+    }
+    let mut segment_ids: Vec<&u8> = led_config.segments.keys().collect();
+    segment_ids.sort();
+    for id in segment_ids {
+        let seg_config = &led_config.segments[id];
+        if let LEDScheduleSpec::ByName(schedule_name) = &seg_config.schedule {
+            if !cfg.schedule.contains_key(schedule_name) {
+                problems.push(format!(
+                    "{} segment {} refers to schedule '{}', which doesn't exist.",
+                    label, id, schedule_name
+                ));
+            }
+        }
+        if seg_config.min_bri > seg_config.max_bri {
+            problems.push(format!(
+                "{} segment {} has min_bri ({}) greater than max_bri ({}).",
+                label, id, seg_config.min_bri, seg_config.max_bri
+            ));
+        }
+    }
+    problems
+}
+
+/// The year keyframe order is checked over. Any year covers every
+/// sunrise/sunset a schedule will see; a fixed one means a config passes or
+/// fails the same whatever day it's checked on.
+const VALIDATE_YEAR: i32 = 2024;
+
+/// Which of the scheduler's event lists an item goes in. Each list is
+/// blended on its own, so order only matters between items in the same one.
+fn keyframe_list(change: &WLEDChange) -> Option<&'static str> {
+    match change {
+        WLEDChange::Brightness(_) => Some("Brightness"),
+        WLEDChange::Preset(_) => Some("Preset"),
+        WLEDChange::Power(_) => Some("Power"),
+        WLEDChange::Cct(_) => Some("Cct"),
+        WLEDChange::Color(_) => Some("Color"),
+        WLEDChange::Effect(_) => Some("Effect"),
+        WLEDChange::Palette(_) => Some("Palette"),
+        WLEDChange::Speed(_) => Some("Speed"),
+        WLEDChange::Intensity(_) => Some("Intensity"),
+        WLEDChange::Playlist(_) | WLEDChange::StopPlaylist => Some("Playlist"),
+        WLEDChange::Nightlight { .. } => Some("Nightlight"),
+        WLEDChange::None => None,
+    }
+}
+
+fn validate_schedule(cfg: &Config, schedule: &WLEDSchedule) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    let bri_count = schedule
+        .iter()
+        .filter(|i| matches!(i.change, WLEDChange::Brightness(_)))
+        .count();
+    let preset_count = schedule
+        .iter()
+        .filter(|i| matches!(i.change, WLEDChange::Preset(_)))
+        .count();
+    if bri_count < 2 {
+        problems.push(format!(
+            "needs at least two Brightness keyframes, found {}.",
+            bri_count
+        ));
+    }
+    if preset_count == 1 {
+        problems.push(
+            "has a single Preset entry; use none, or at least two so it can change back."
+                .to_string(),
+        );
+    }
+    // Brightness items limited by days or dates can leave days with nothing
+    // to blend between in the window the scheduler resolves, which would
+    // leave those lights unscheduled. Every day needs a keyframe on an
+    // earlier day and one on a later day within that window.
+    if bri_count >= 2 {
+        let window = crate::util::SCHEDULE_WINDOW_DAYS;
+        let first = chrono::NaiveDate::from_ymd_opt(VALIDATE_YEAR, 1, 1).expect("Valid date");
+        let has_bri = |offset: i64| {
+            let date = first + chrono::Duration::days(offset);
+            schedule
+                .iter()
+                .any(|i| matches!(i.change, WLEDChange::Brightness(_)) && i.is_active_on(date))
+        };
+        let active: Vec<bool> = (-window..366 + window).map(has_bri).collect();
+        let uncovered = (0..366).find(|day| {
+            let at = (day + window) as usize;
+            !active[at - window as usize..at].contains(&true)
+                || !active[at + 1..=at + window as usize].contains(&true)
+        });
+        if let Some(day) = uncovered {
+            problems.push(format!(
+                "has no Brightness keyframes within {} days before or after {}; its lights would go unscheduled.",
+                window,
+                first + chrono::Duration::days(day)
+            ));
+        }
+    }
+
+    for (idx, item) in schedule.iter().enumerate() {
+        match &item.change {
+            WLEDChange::Cct(kelvin) if !(1900..=10091).contains(kelvin) => problems.push(format!(
+                "entry {} has Cct({}); WLED takes 1900 to 10091 Kelvin.",
+                idx + 1,
+                kelvin
+            )),
+            WLEDChange::Color(color) if !(3..=4).contains(&color.len()) => problems.push(format!(
+                "entry {} has a Color with {} channels; use [r, g, b] or [r, g, b, w].",
+                idx + 1,
+                color.len()
+            )),
+            WLEDChange::Nightlight { duration: 0, .. } => problems.push(format!(
+                "entry {} has a Nightlight with no duration; give it 1-255 minutes.",
+                idx + 1
+            )),
+            _ => (),
+        }
+    }
+
+    // Keyframes of each kind have to stay in the order they're written once
+    // sun times are resolved; otherwise e.g. a dim at 20:00 listed before one
+    // at sunset would swap places (and the light brighten again) in summer.
+    // Report the first date that breaks for each kind, rather than one line
+    // per day.
+    let tz = cfg.zone();
+    let start = chrono::NaiveDate::from_ymd_opt(VALIDATE_YEAR, 1, 1).expect("Valid date");
+    let mut lists: Vec<&str> = schedule
+        .iter()
+        .filter_map(|i| keyframe_list(&i.change))
+        .collect();
+    lists.sort();
+    lists.dedup();
+    for list in lists {
+        let mut date = start;
+        while date.year() == VALIDATE_YEAR {
+            let keyframes: Vec<(usize, u64)> = schedule
+                .iter()
+                .enumerate()
+                .filter(|(_, i)| keyframe_list(&i.change) == Some(list) && i.is_active_on(date))
+                .map(|(idx, i)| {
+                    (
+                        idx,
+                        i.time
+                            .to_timestamp_on(date, &tz, cfg.lat as f64, cfg.lon as f64),
+                    )
+                })
+                .collect();
+            if let Some(pair) = keyframes.windows(2).find(|pair| pair[0].1 > pair[1].1) {
+                problems.push(format!(
+                    "entry {} ({:?}) comes before entry {} ({:?}) on {}; {} keyframes must be in time order.",
+                    pair[1].0 + 1,
+                    schedule[pair[1].0].time,
+                    pair[0].0 + 1,
+                    schedule[pair[0].0].time,
+                    date,
+                    list
+                ));
+                break;
+            }
+            date += chrono::Duration::days(1);
+        }
+    }
+    problems
+}
+
 #[cfg(test)]
 /* These tests are f%^(@*# awful, because they mutate files. */
 mod tests {
     use super::*;
 
+    fn item(time: ScheduleTime, change: WLEDChange) -> WLEDScheduleItem {
+        WLEDScheduleItem {
+            time,
+            change,
+            ..Default::default()
+        }
+    }
+
+    /// A config with nothing wrong with it, for each test to break one way.
+    fn valid_config() -> Config {
+        let cfg = Config {
+            lat: 49.,
+            lon: -124.,
+            timezone: Some("America/Vancouver".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_config(&cfg).is_empty());
+        assert!(config_warnings(&cfg).is_empty());
+        cfg
+    }
+
+    fn assert_reported(problems: &[String], start: &str) {
+        assert!(
+            problems.iter().any(|problem| problem.starts_with(start)),
+            "Expected {:?} in {:#?}",
+            start,
+            problems
+        );
+    }
+
+    #[test]
+    fn test_validate_led_config() {
+        let mut cfg = valid_config();
+        cfg.leds.insert(
+            "wled-a".to_string(),
+            LEDBrightnessConfig {
+                schedule: LEDScheduleSpec::ByName("missing".to_string()),
+                min_bri: 200,
+                max_bri: 100,
+                segments: HashMap::from([(
+                    3,
+                    SegmentConfig {
+                        schedule: LEDScheduleSpec::ByName("gone".to_string()),
+                        min_bri: 0,
+                        max_bri: 255,
+                        curve: BrightnessCurve::Linear,
+                    },
+                )]),
+                ..Default::default()
+            },
+        );
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+        assert_reported(&problems, "LED 'wled-a' refers to schedule 'missing'");
+        assert_reported(&problems, "LED 'wled-a' has min_bri (200)");
+        assert_reported(
+            &problems,
+            "LED 'wled-a' segment 3 refers to schedule 'gone'",
+        );
+    }
+
+    #[test]
+    fn test_validate_keyframe_order() {
+        let mut cfg = valid_config();
+        let noon = ScheduleTime::Time(chrono::NaiveTime::from_hms_opt(12, 0, 0).unwrap());
+        cfg.schedule.insert(
+            "fine".to_string(),
+            vec![
+                item(ScheduleTime::Sunrise, WLEDChange::Brightness(1.0)),
+                item(ScheduleTime::Sunrise, WLEDChange::Cct(6500)),
+                item(noon.clone(), WLEDChange::Brightness(0.5)),
+                item(ScheduleTime::Sunset, WLEDChange::Brightness(0.0)),
+                item(
+                    ScheduleTime::Sunset,
+                    WLEDChange::Color(vec![255, 80, 0, 40]),
+                ),
+                // Only the order within each kind of keyframe matters.
+                item(noon, WLEDChange::Power(true)),
+            ],
+        );
+        assert!(validate_config(&cfg).is_empty());
+
+        // Solar noon is never after sunset.
+        cfg.schedule.insert(
+            "backwards".to_string(),
+            vec![
+                item(ScheduleTime::Sunset, WLEDChange::Brightness(1.0)),
+                item(ScheduleTime::SolarNoon, WLEDChange::Brightness(0.0)),
+            ],
+        );
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 1, "{:#?}", problems);
+        assert_reported(&problems, "Schedule 'backwards': entry 2");
+    }
+
+    #[test]
+    fn test_validate_colors() {
+        let mut cfg = valid_config();
+        cfg.schedule.insert(
+            "colorful".to_string(),
+            vec![
+                item(ScheduleTime::Sunrise, WLEDChange::Brightness(1.0)),
+                item(ScheduleTime::Sunrise, WLEDChange::Cct(12000)),
+                item(ScheduleTime::Sunset, WLEDChange::Brightness(0.0)),
+                item(ScheduleTime::Sunset, WLEDChange::Color(vec![255, 80])),
+            ],
+        );
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 2, "{:#?}", problems);
+        assert_reported(&problems, "Schedule 'colorful': entry 2 has Cct(12000)");
+        assert_reported(&problems, "Schedule 'colorful': entry 4 has a Color with 2");
+    }
+
+    #[test]
+    fn test_validate_brightness_keyframes() {
+        let mut cfg = valid_config();
+        cfg.schedule.insert(
+            "lonely".to_string(),
+            vec![
+                item(ScheduleTime::Sunrise, WLEDChange::Brightness(1.0)),
+                item(ScheduleTime::Sunset, WLEDChange::Preset(2)),
+            ],
+        );
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 2, "{:#?}", problems);
+        assert_reported(&problems, "Schedule 'lonely': needs at least two");
+        assert_reported(&problems, "Schedule 'lonely': has a single Preset");
+    }
+
+    #[test]
+    fn test_validate_devices() {
+        let mut cfg = valid_config();
+        cfg.devices.insert(
+            "wled-vlan".to_string(),
+            StaticDevice {
+                host: "10.0.20.5".to_string(),
+                port: 80,
+            },
+        );
+        // Unused devices are only worth a warning.
+        assert!(validate_config(&cfg).is_empty());
+        let warnings = config_warnings(&cfg);
+        assert_eq!(warnings.len(), 1, "{:#?}", warnings);
+        assert_reported(&warnings, "Device 'wled-vlan' isn't in leds or any group");
+
+        cfg.tasmota.insert(
+            "porch-bulb".to_string(),
+            StaticDevice {
+                host: "10.0.20.9".to_string(),
+                port: 80,
+            },
+        );
+        let warnings = config_warnings(&cfg);
+        assert_eq!(warnings.len(), 2, "{:#?}", warnings);
+        assert_reported(&warnings, "Tasmota 'porch-bulb' isn't in leds");
+    }
+
+    #[test]
+    fn test_validate_groups() {
+        let mut cfg = valid_config();
+        cfg.groups.insert(
+            "kitchen".to_string(),
+            LEDGroup {
+                members: GroupMatch::Regex("^wled-vlan-\\d+$".to_string().try_into().unwrap()),
+                priority: 0,
+                config: LEDBrightnessConfig {
+                    schedule: LEDScheduleSpec::ByName("nope".to_string()),
+                    ..Default::default()
+                },
+            },
+        );
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 1, "{:#?}", problems);
+        assert_reported(&problems, "Group 'kitchen' refers to schedule 'nope'");
+
+        // The group's pattern doesn't take in this one.
+        cfg.devices.insert(
+            "wled-vlan".to_string(),
+            StaticDevice {
+                host: "10.0.20.5".to_string(),
+                port: 80,
+            },
+        );
+        assert_reported(&config_warnings(&cfg), "Device 'wled-vlan' isn't in leds");
+    }
+
+    #[test]
+    fn test_validate_tasmota_probe() {
+        let mut cfg = valid_config();
+        cfg.tasmota_probe.push("10.0.0.0/8".to_string());
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 1, "{:#?}", problems);
+        assert_reported(&problems, "tasmota_probe: '10.0.0.0/8' needs a prefix");
+    }
+
+    #[test]
+    fn test_validate_timezone() {
+        let mut cfg = valid_config();
+        cfg.timezone = Some("America/Vancuover".to_string());
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 1, "{:#?}", problems);
+        assert_reported(&problems, "Invalid timezone 'America/Vancuover'");
+        // Anything else still gets a zone, if the wrong one.
+        assert_eq!(cfg.zone(), chrono_tz::UTC);
+    }
+
+    #[test]
+    fn test_validate_mqtt() {
+        let mut cfg = valid_config();
+        cfg.mqtt = Some(MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "test".to_string(),
+            username: None,
+            password: None,
+            topic_prefix: "home/#".to_string(),
+        });
+        let problems = validate_config(&cfg);
+        assert_eq!(problems.len(), 1, "{:#?}", problems);
+        assert_reported(&problems, "mqtt: topic_prefix 'home/#'");
+    }
+
+    #[test]
+    fn test_bad_group_regex() {
+        // Regexes are compiled as the config is read, so a bad one fails the load.
+        let err = ron::de::from_str::<GroupMatch>(r#"Regex("wled-(vlan")"#).unwrap_err();
+        assert!(err.code.to_string().contains("unclosed group"), "{}", err);
+        let ok = ron::de::from_str::<GroupMatch>(r#"Regex("^WLED-[0-9]+")"#).unwrap();
+        assert!(ok.matches("wled-12", None));
+    }
+
+    #[test]
+    fn test_validate_schedule_coverage() {
+        let cfg = valid_config();
+        let time = |h| ScheduleTime::Time(chrono::NaiveTime::from_hms_opt(h, 0, 0).unwrap());
+        // Both brightness keyframes only run over the holidays, so the rest of
+        // the year has nothing to blend between.
+        let holidays = |h, bri| WLEDScheduleItem {
+            time: time(h),
+            change: WLEDChange::Brightness(bri),
+            dates: Some(DateRange {
+                from: (12, 1),
+                until: (1, 6),
+            }),
+            ..Default::default()
+        };
+        let problems = validate_schedule(&cfg, &vec![holidays(8, 1.0), holidays(20, 0.0)]);
+        assert_eq!(problems.len(), 1, "{:#?}", problems);
+        assert_reported(&problems, "has no Brightness keyframes within 7 days");
+
+        // Weekend-only keyframes are never more than a week apart.
+        let weekends = |h, bri| WLEDScheduleItem {
+            time: time(h),
+            change: WLEDChange::Brightness(bri),
+            days: ActiveDays::Weekends,
+            ..Default::default()
+        };
+        assert!(validate_schedule(&cfg, &vec![weekends(8, 1.0), weekends(20, 0.0)]).is_empty());
+    }
+
+    #[test]
+    fn test_ron_error_position() {
+        let path = std::env::temp_dir().join("wled-doppler-test-bad-config.ron");
+        std::fs::write(&path, "(\n    lat: 49.0,\n    lon: nope,\n)").unwrap();
+        let err = load_config(Some(path.clone())).unwrap_err().to_string();
+        std::fs::remove_file(&path).ok();
+        assert!(
+            err.starts_with(&format!("{}:3:", path.display())),
+            "{}",
+            err
+        );
+    }
+
     // #[test]
     // fn test_setup_homedir() {
     //     let cfg = load_config(None).expect("Failed to load config from file.");
@@ -0,0 +1,769 @@
+/// One pass of the schedule over every WLED we know about.
+use crate::backend::LightBackend;
+use crate::live::LiveState;
+use crate::tasmota::Tasmota;
+use crate::types::*;
+use crate::util::{calc_led_bri, calc_segments_scheduled, led_check_manual_change, Schedules};
+use chrono::DateTime;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What happened to one WLED in a cycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Ok,
+    Failed,
+    NoConfig,
+    Unscheduled,
+    Held,
+    /// Failing lately, and not due another try yet.
+    BackingOff,
+    /// Left the network, as far as mDNS knows.
+    Gone,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CycleCounts {
+    pub ok: usize,
+    pub failed: usize,
+    pub noconfig: usize,
+    pub unscheduled: usize,
+    pub held: usize,
+    pub backing_off: usize,
+    pub gone: usize,
+}
+
+impl CycleCounts {
+    pub fn merge(&mut self, other: CycleCounts) {
+        self.ok += other.ok;
+        self.failed += other.failed;
+        self.noconfig += other.noconfig;
+        self.unscheduled += other.unscheduled;
+        self.held += other.held;
+        self.backing_off += other.backing_off;
+        self.gone += other.gone;
+    }
+
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Ok => self.ok += 1,
+            Outcome::Failed => self.failed += 1,
+            Outcome::NoConfig => self.noconfig += 1,
+            Outcome::Unscheduled => self.unscheduled += 1,
+            Outcome::Held => self.held += 1,
+            Outcome::BackingOff => self.backing_off += 1,
+            Outcome::Gone => self.gone += 1,
+        }
+    }
+}
+
+/// Updates every WLED in `found_wled`, up to `config.max_parallel_updates` at
+/// a time, so a slow or dead controller only holds up its own worker.
+pub fn run_cycle(
+    config: &Config,
+    schedules: &Schedules,
+    now: DateTime<chrono_tz::Tz>,
+    found_wled: &mut HashMap<String, WLED>,
+) -> CycleCounts {
+    let counts = Mutex::new(CycleCounts::default());
+    for_each_device(config, found_wled, |name, wled| {
+        let outcome = update_led(config, schedules, now, name, wled);
+        counts.lock().unwrap().add(outcome);
+    });
+    counts.into_inner().unwrap()
+}
+
+/// Runs `job` on every device with a pool of `config.max_parallel_updates`
+/// worker threads.
+fn for_each_device<T, F>(config: &Config, found_wled: &mut HashMap<String, T>, job: F)
+where
+    T: Send,
+    F: Fn(&str, &mut T) + Sync,
+{
+    let workers = config
+        .max_parallel_updates
+        .clamp(1, found_wled.len().max(1));
+    let jobs = Mutex::new(found_wled.iter_mut().collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = jobs.lock().unwrap().pop();
+                let Some((name, wled)) = next else {
+                    break;
+                };
+                job(name, wled);
+            });
+        }
+    });
+}
+
+/// Snapshots every scheduled WLED just before LedFx takes them over.
+pub fn ledfx_started(config: &Config, found_wled: &mut HashMap<String, WLED>) {
+    for_each_device(config, found_wled, |name, wled| {
+        if wled.health.state == HealthState::Gone || config.led_config(name, wled.info()).is_none()
+        {
+            return;
+        }
+        match wled.read_state() {
+            Ok(state) => wled.memory.ledfx_snapshot = Some(state),
+            Err(err) => {
+                wled.memory.ledfx_snapshot = None;
+                debug!("Couldn't snapshot WLED '{}' for LedFx: {:?}", name, err);
+            }
+        }
+    });
+}
+
+/// Hands every scheduled WLED back from LedFx, as its `ledfx_handoff` says.
+pub fn ledfx_stopped(config: &Config, found_wled: &mut HashMap<String, WLED>) {
+    for_each_device(config, found_wled, |name, wled| {
+        let Some(led_config) = config.led_config(name, wled.info()) else {
+            return;
+        };
+        let snapshot = wled.memory.ledfx_snapshot.take();
+        match (&led_config.ledfx_handoff, snapshot) {
+            (LedFxHandoff::Restore, Some(snapshot)) => {
+                info!("Restoring WLED '{}' to how it was before LedFx.", name);
+                if wled.restore_state(&snapshot).is_err() {
+                    wled.memory.forget_sent();
+                }
+            }
+            (LedFxHandoff::Restore, None) => {
+                debug!("No snapshot of WLED '{}', rescheduling it instead.", name);
+                wled.memory.forget_sent();
+            }
+            (LedFxHandoff::Reschedule, _) => wled.memory.forget_sent(),
+        }
+    });
+}
+
+/// Brings one WLED in line with its schedule, sending only what changed
+/// since the last cycle.
+pub fn update_led(
+    config: &Config,
+    schedules: &Schedules,
+    now: DateTime<chrono_tz::Tz>,
+    name: &str,
+    wled: &mut WLED,
+) -> Outcome {
+    if wled.health.state == HealthState::Gone {
+        return Outcome::Gone;
+    }
+    // Only follow WLEDs we schedule; the rest needn't hold a connection open.
+    let configured = config.led_config(name, wled.info()).is_some();
+    if config.live_state && configured {
+        if wled.live.is_none() {
+            match LiveState::spawn(
+                name,
+                &wled.device.url,
+                Duration::from_millis(config.http_connect_timeout_ms),
+            ) {
+                Ok(live) => wled.live = Some(live),
+                Err(err) => warn!("Couldn't follow WLED '{}' live: {:?}", name, err),
+            }
+        }
+        if let Some(state) = wled.live.as_ref().and_then(|live| live.take()) {
+            wled.state = Some(state);
+        }
+    } else {
+        wled.live = None;
+    }
+    // The WLED is its own backend, so lend out its bookkeeping alongside it.
+    let mut memory = std::mem::take(&mut wled.memory);
+    let mut health = std::mem::take(&mut wled.health);
+    let outcome = update_light(config, schedules, now, name, wled, &mut memory, &mut health);
+    wled.memory = memory;
+    wled.health = health;
+    outcome
+}
+
+/// Brings any light in line with its schedule, given what we remember
+/// sending it and how it has been answering.
+pub fn update_light(
+    config: &Config,
+    schedules: &Schedules,
+    now: DateTime<chrono_tz::Tz>,
+    name: &str,
+    light: &mut dyn LightBackend,
+    memory: &mut LedMemory,
+    health: &mut DeviceHealth,
+) -> Outcome {
+    if light.info().is_none() && config.has_info_groups() && health.should_retry(now.timestamp()) {
+        if let Err(err) = light.discover() {
+            debug!("Couldn't read info from light '{}': {:?}", name, err);
+        }
+    }
+    let led_config = match config.led_config(name, light.info()) {
+        Some(led_config) => led_config,
+        None => {
+            debug!("No config for light '{}', leaving it alone.", name);
+            return Outcome::NoConfig;
+        }
+    };
+    let schedule = match schedules.get(&led_config.schedule) {
+        Some(schedule) => schedule,
+        None => {
+            if let LEDScheduleSpec::ByName(sched_name) = &led_config.schedule {
+                warn!(
+                    "Light '{}' refers to missing schedule '{}'.",
+                    name, sched_name
+                );
+            }
+            return Outcome::Unscheduled;
+        }
+    };
+    if !health.should_retry(now.timestamp()) {
+        return Outcome::BackingOff;
+    }
+    if let Some(until) = memory.held_until {
+        if now.timestamp() < until {
+            return Outcome::Held;
+        }
+        info!("Hold on light '{}' is over, back on schedule.", name);
+        // Whatever was done by hand, put everything back.
+        memory.held_until = None;
+        memory.forget_sent();
+    }
+    let Some(state) = schedule.state_at(now.timestamp()) else {
+        warn!(
+            "Light '{}' has no brightness keyframes around now to follow; leaving it alone.",
+            name
+        );
+        return Outcome::Unscheduled;
+    };
+    // Kept until the new state is sent, so a light held for a change by hand
+    // is still checked against what it was last told.
+    let last_state = memory.last_command.as_ref();
+    // Checked every cycle, not just when the schedule moves, so a change made
+    // while it's holding steady is caught too. A running nightlight changes
+    // brightness all by itself.
+    if let Some(last) = last_state.filter(|last| {
+        led_config.manual_override != OverridePolicy::Ignore && last.nightlight.is_none()
+    }) {
+        match led_check_manual_change(light, led_config, last) {
+            Ok(Some(change)) => {
+                let until = match led_config.manual_override {
+                    OverridePolicy::Hold(seconds) => now.timestamp() + seconds as i64,
+                    _ => schedule
+                        .next_keyframe_after(now.timestamp())
+                        .map(|ts| ts as i64)
+                        .unwrap_or(now.timestamp() + 86400),
+                };
+                info!(
+                    "Light '{}' was changed by hand ({}); leaving it alone until {}.",
+                    name,
+                    change,
+                    chrono::DateTime::from_timestamp(until, 0)
+                        .map(|until| until.with_timezone(&now.timezone()).to_rfc3339())
+                        .unwrap_or_default()
+                );
+                memory.held_until = Some(until);
+                return Outcome::Held;
+            }
+            Ok(None) => (),
+            Err(err) => {
+                debug!("Couldn't check light '{}' for changes: {:?}", name, err)
+            }
+        }
+    }
+    let new_bri = calc_led_bri(led_config, state.bri);
+    let tt = led_config.transition_tt(config.cycle_seconds);
+    let last_bri = last_state.map(|last| calc_led_bri(led_config, last.bri));
+    let last_preset = last_state.and_then(|last| last.preset);
+    let last_power = last_state.and_then(|last| last.power);
+    debug!(
+        "Light '{}' should be at bri:{} preset:{:?} power:{:?} cct:{:?} color:{:?}",
+        name, new_bri, state.preset, state.power, state.cct, state.color
+    );
+
+    let power = state.power;
+    let power_changed = power.is_some() && last_power != power;
+    // Once a nightlight is done, the device is wherever WLED left it.
+    let nightlight_ended =
+        state.nightlight.is_none() && last_state.is_some_and(|last| last.nightlight.is_some());
+    let resync = power_changed || nightlight_ended;
+    let mut preset_changed = false;
+    let mut result: anyhow::Result<()> = Ok(());
+    if let Some(nightlight) = &state.nightlight {
+        // WLED runs the nightlight fade itself; keep out of its way.
+        if last_state.and_then(|last| last.nightlight.as_ref()) != Some(nightlight) {
+            info!("Starting light '{}' nightlight {:?}", name, nightlight);
+            result = light.set_nightlight(nightlight);
+        }
+    } else if power == Some(false) {
+        // Presets and brightness both switch a WLED back on, so
+        // hold them until the schedule powers it up again.
+        if resync {
+            info!("Setting light '{}' power to false", name);
+            result = light.set_power(false);
+        }
+    } else {
+        if power_changed {
+            info!("Setting light '{}' power to true", name);
+            result = result.and(light.set_power(true));
+        }
+        if let Some(new_preset) = state.preset {
+            if resync || last_preset != Some(new_preset) {
+                info!("Setting light '{}' to preset {}", name, new_preset);
+                preset_changed = true;
+                result = result.and(light.set_preset(new_preset, tt));
+            }
+        }
+        if let Some(playlist) = state.playlist {
+            if resync || last_state.and_then(|last| last.playlist) != Some(playlist) {
+                info!("Setting light '{}' playlist to {:?}", name, playlist);
+                preset_changed = true;
+                result = result.and(light.set_playlist(playlist));
+            }
+        }
+        if resync || last_bri != Some(new_bri) {
+            info!("Setting light '{}' to brightness {}", name, new_bri);
+            result = result.and(light.set_brightness(new_bri, tt));
+        }
+        // A preset brings its own colors and effect, so put ours back on top.
+        if state.has_segment_changes()
+            && (resync
+                || preset_changed
+                || !last_state.is_some_and(|last| last.same_segment_changes(&state)))
+        {
+            info!(
+                "Setting light '{}' to cct:{:?} color:{:?} effect:{:?} palette:{:?} speed:{:?} intensity:{:?}",
+                name,
+                state.cct,
+                state.color,
+                state.effect,
+                state.palette,
+                state.speed,
+                state.intensity
+            );
+            result = result.and(light.set_color(&state, tt));
+        }
+        if !led_config.segments.is_empty() {
+            let segments = calc_segments_scheduled(schedules, led_config, now.timestamp());
+            if resync || preset_changed || memory.last_segments.as_ref() != Some(&segments) {
+                info!("Setting light '{}' segments to {:?}", name, segments);
+                result = result.and(light.set_segments(&segments, tt));
+                memory.last_segments = Some(segments);
+            }
+        }
+    }
+    match result {
+        Ok(()) => memory.last_command = Some(state),
+        // Forget what we sent so the next try resends everything.
+        Err(_) => memory.forget_sent(),
+    }
+    record_result(config, now, name, health, &result)
+}
+
+/// Tracks a device's health from the result of updating it, logging when
+/// that changes, and says how the update went.
+fn record_result(
+    config: &Config,
+    now: DateTime<chrono_tz::Tz>,
+    name: &str,
+    health: &mut DeviceHealth,
+    result: &anyhow::Result<()>,
+) -> Outcome {
+    match result {
+        Ok(()) => {
+            if let Some(old) = health.record_success(now.timestamp()) {
+                info!("Device '{}' is back online (was {:?}).", name, old);
+            }
+            Outcome::Ok
+        }
+        Err(err) => {
+            if health
+                .record_failure(now.timestamp(), config.cycle_seconds, err.to_string())
+                .is_some()
+            {
+                let retry = health.backoff_seconds(config.cycle_seconds);
+                match health.state {
+                    HealthState::Offline => error!(
+                        "Device '{}' is offline after {} failures; retrying at most every {}s: {}",
+                        name, health.consecutive_failures, retry, err
+                    ),
+                    _ => warn!(
+                        "Device '{}' is failing, retrying in {}s: {}",
+                        name, retry, err
+                    ),
+                }
+            }
+            Outcome::Failed
+        }
+    }
+}
+
+/// Updates every Tasmota device, like `run_cycle` does for WLEDs.
+pub fn run_tasmota_cycle(
+    config: &Config,
+    schedules: &Schedules,
+    now: DateTime<chrono_tz::Tz>,
+    found_tasmota: &mut HashMap<String, Tasmota>,
+) -> CycleCounts {
+    let counts = Mutex::new(CycleCounts::default());
+    for_each_device(config, found_tasmota, |name, device| {
+        let mut memory = std::mem::take(&mut device.memory);
+        let mut health = std::mem::take(&mut device.health);
+        let outcome = update_light(
+            config,
+            schedules,
+            now,
+            name,
+            device,
+            &mut memory,
+            &mut health,
+        );
+        device.memory = memory;
+        device.health = health;
+        counts.lock().unwrap().add(outcome);
+    });
+    counts.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::{MockCommand, MockLight};
+    use crate::util::{http_client, wled_device};
+    use reqwest::Url;
+    use std::time::{Duration, Instant};
+
+    fn test_wled(name: &str, url: &str, client: &reqwest::blocking::Client) -> WLED {
+        WLED {
+            state: None,
+            address: "127.0.0.1".parse().unwrap(),
+            name: name.to_string(),
+            device: wled_device(Url::parse(url).unwrap(), client),
+            memory: LedMemory::default(),
+            health: DeviceHealth::default(),
+            live: None,
+        }
+    }
+
+    #[test]
+    fn test_run_cycle() {
+        let mut config = Config {
+            lat: 49.,
+            lon: -124.,
+            timezone: Some("America/Vancouver".to_string()),
+            cycle_seconds: 10.,
+            http_connect_timeout_ms: 200,
+            http_timeout_ms: 200,
+            ..Default::default()
+        };
+        for name in ["dead-1", "dead-2", "dead-3", "unscheduled"] {
+            config.leds.insert(
+                name.to_string(),
+                LEDBrightnessConfig {
+                    schedule: match name {
+                        "unscheduled" => LEDScheduleSpec::None,
+                        _ => LEDScheduleSpec::Default,
+                    },
+                    ..Default::default()
+                },
+            );
+        }
+        let client = http_client(&config).unwrap();
+        // Nothing listens on port 1, so every request fails.
+        let mut found_wled: HashMap<String, WLED> =
+            ["dead-1", "dead-2", "dead-3", "unscheduled", "unconfigured"]
+                .iter()
+                .map(|name| {
+                    (
+                        name.to_string(),
+                        test_wled(name, "http://127.0.0.1:1/", &client),
+                    )
+                })
+                .collect();
+        found_wled.get_mut("dead-1").unwrap().memory.held_until = Some(i64::MAX);
+
+        let start = Instant::now();
+        let counts = run_cycle(
+            &config,
+            &Schedules::new(&config, config.now()),
+            config.now(),
+            &mut found_wled,
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            counts,
+            CycleCounts {
+                ok: 0,
+                failed: 2,
+                noconfig: 1,
+                unscheduled: 1,
+                held: 1,
+                backing_off: 0,
+                gone: 0,
+            }
+        );
+        assert!(found_wled["dead-2"].memory.last_command.is_none());
+        assert_eq!(found_wled["dead-2"].health.state, HealthState::Degraded);
+        assert_eq!(found_wled["dead-2"].health.consecutive_failures, 1);
+
+        // Failing devices wait out their backoff instead of retrying every cycle.
+        let counts = run_cycle(
+            &config,
+            &Schedules::new(&config, config.now()),
+            config.now(),
+            &mut found_wled,
+        );
+        assert_eq!((counts.failed, counts.backing_off), (0, 2));
+
+        crate::util::mark_wled_gone("dead-2", &mut found_wled);
+        let counts = run_cycle(
+            &config,
+            &Schedules::new(&config, config.now()),
+            config.now(),
+            &mut found_wled,
+        );
+        assert_eq!((counts.backing_off, counts.gone), (1, 1));
+        assert_eq!(found_wled["dead-1"].memory.held_until, Some(i64::MAX));
+    }
+
+    #[test]
+    fn test_playlist_not_manual() {
+        use chrono::{NaiveTime, TimeZone};
+        let keyframe = |h: u32, change: WLEDChange| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap()),
+            change,
+            ..Default::default()
+        };
+        let mut config = Config {
+            lat: 49.,
+            lon: -124.,
+            timezone: Some("America/Vancouver".to_string()),
+            ..Default::default()
+        };
+        config.schedule.insert(
+            "default".to_string(),
+            WLEDSchedule::from([
+                keyframe(0, WLEDChange::Brightness(1.0)),
+                keyframe(18, WLEDChange::Playlist(3)),
+                keyframe(23, WLEDChange::Brightness(1.0)),
+            ]),
+        );
+        config.leds.insert(
+            "mock".to_string(),
+            LEDBrightnessConfig {
+                schedule: LEDScheduleSpec::Default,
+                min_bri: 0,
+                max_bri: 200,
+                manual_override: OverridePolicy::Hold(3600),
+                ..Default::default()
+            },
+        );
+        let mut light = MockLight::default();
+        let mut memory = LedMemory::default();
+        let mut health = DeviceHealth::default();
+        let evening = config
+            .zone()
+            .with_ymd_and_hms(2024, 6, 7, 18, 0, 0)
+            .unwrap();
+        let mut update = |light: &mut MockLight, minutes| {
+            let now = evening + chrono::Duration::minutes(minutes);
+            update_light(
+                &config,
+                &Schedules::new(&config, now),
+                now,
+                "mock",
+                light,
+                &mut memory,
+                &mut health,
+            )
+        };
+
+        assert_eq!(update(&mut light, 0), Outcome::Ok);
+        assert!(light
+            .take_commands()
+            .contains(&MockCommand::Playlist(PlaylistState::Running(3))));
+        // The playlist moves on to a dimmer preset of its own.
+        light.state.ps = Some(7);
+        light.state.bri = Some(40);
+        assert_eq!(update(&mut light, 10), Outcome::Ok);
+        assert!(light.take_commands().is_empty());
+        // Switching it off still counts as a change by hand.
+        light.state.on = Some(false);
+        assert_eq!(update(&mut light, 20), Outcome::Held);
+    }
+
+    #[test]
+    fn test_ledfx_stopped() {
+        let mut config = Config::default();
+        config.leds.insert(
+            "wled-restore".to_string(),
+            LEDBrightnessConfig {
+                ledfx_handoff: LedFxHandoff::Restore,
+                ..Default::default()
+            },
+        );
+        config.leds.insert(
+            "wled-reschedule".to_string(),
+            LEDBrightnessConfig::default(),
+        );
+        let client = http_client(&config).unwrap();
+        let mut found_wled: HashMap<String, WLED> =
+            ["wled-restore", "wled-reschedule", "unconfigured"]
+                .iter()
+                .map(|name| {
+                    let mut wled = test_wled(name, "http://127.0.0.1:1/", &client);
+                    wled.memory.last_command = Some(LEDState::default());
+                    (name.to_string(), wled)
+                })
+                .collect();
+        // Without a snapshot to restore, the schedule is sent again instead.
+        ledfx_stopped(&config, &mut found_wled);
+        assert!(found_wled["wled-restore"].memory.last_command.is_none());
+        assert!(found_wled["wled-reschedule"].memory.last_command.is_none());
+        assert!(found_wled["unconfigured"].memory.last_command.is_some());
+    }
+
+    #[test]
+    fn test_schedule_day_on_mock() {
+        use chrono::{NaiveTime, TimeZone};
+        let keyframe = |h: u32, change: WLEDChange| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap()),
+            change,
+            ..Default::default()
+        };
+        let mut config = Config {
+            lat: 49.,
+            lon: -124.,
+            timezone: Some("America/Vancouver".to_string()),
+            cycle_seconds: 600.,
+            ..Default::default()
+        };
+        config.schedule.insert(
+            "default".to_string(),
+            WLEDSchedule::from([
+                keyframe(0, WLEDChange::Preset(1)),
+                keyframe(6, WLEDChange::Power(true)),
+                keyframe(6, WLEDChange::Brightness(0.0)),
+                keyframe(8, WLEDChange::Brightness(1.0)),
+                keyframe(18, WLEDChange::Preset(2)),
+                keyframe(20, WLEDChange::Brightness(1.0)),
+                keyframe(22, WLEDChange::Brightness(0.0)),
+                keyframe(23, WLEDChange::Power(false)),
+            ]),
+        );
+        config.leds.insert(
+            "mock".to_string(),
+            LEDBrightnessConfig {
+                schedule: LEDScheduleSpec::Default,
+                min_bri: 0,
+                max_bri: 200,
+                manual_override: OverridePolicy::Hold(3600),
+                ..Default::default()
+            },
+        );
+        let mut light = MockLight::default();
+        let mut memory = LedMemory::default();
+        let mut health = DeviceHealth::default();
+        let midnight = config.zone().with_ymd_and_hms(2024, 6, 7, 0, 0, 0).unwrap();
+
+        // Every ten minutes for a day, with someone dimming it by hand at 19:00.
+        let mut sent: Vec<(i64, Outcome, Vec<MockCommand>)> = Vec::new();
+        for minute in (0..24 * 60).step_by(10) {
+            if minute == 19 * 60 {
+                light.state.bri = Some(50);
+            }
+            let now = midnight + chrono::Duration::minutes(minute);
+            let outcome = update_light(
+                &config,
+                &Schedules::new(&config, now),
+                now,
+                "mock",
+                &mut light,
+                &mut memory,
+                &mut health,
+            );
+            sent.push((minute, outcome, light.take_commands()));
+        }
+        let at = |h: i64, m: i64| {
+            &sent
+                .iter()
+                .find(|(minute, ..)| *minute == h * 60 + m)
+                .unwrap()
+                .2
+        };
+        let brightness = |commands: &[MockCommand]| {
+            commands.iter().find_map(|command| match command {
+                MockCommand::Brightness(bri) => Some(*bri),
+                _ => None,
+            })
+        };
+
+        // Still off from last night, and left alone until morning.
+        assert_eq!(at(0, 0), &vec![MockCommand::Power(false)]);
+        assert!((10..6 * 60).all(|minute| sent[minute / 10].2.is_empty()));
+        // Switched on with its preset right at 06:00.
+        assert_eq!(
+            at(6, 0)[..2],
+            [MockCommand::Power(true), MockCommand::Preset(1)]
+        );
+        // Then brightened every cycle up to full at 08:00.
+        let ramp: Vec<u8> = (6 * 60..=8 * 60)
+            .step_by(10)
+            .filter_map(|minute| brightness(&sent[minute / 10].2))
+            .collect();
+        assert_eq!(ramp.len(), 13);
+        assert_eq!(ramp.first(), Some(&0));
+        assert!(ramp.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(ramp.last(), Some(&200));
+        assert!(at(12, 0).is_empty());
+        assert_eq!(at(18, 0), &vec![MockCommand::Preset(2)]);
+        // The hand-dimmed light is caught straight away, though the schedule
+        // is holding steady, and held for an hour.
+        assert!(sent
+            .iter()
+            .filter(|(_, outcome, _)| *outcome == Outcome::Held)
+            .map(|(minute, ..)| *minute)
+            .eq((19 * 60..20 * 60).step_by(10)));
+        assert!(at(19, 30).is_empty());
+        assert_eq!(
+            at(20, 0)[..2],
+            [MockCommand::Power(true), MockCommand::Preset(2)]
+        );
+        assert_eq!(brightness(at(20, 0)), Some(200));
+        assert_eq!(brightness(at(21, 0)), Some(100));
+        assert_eq!(at(23, 0), &vec![MockCommand::Power(false)]);
+        assert!((23 * 60 + 10..24 * 60).all(|minute| sent[minute / 10].2.is_empty()));
+
+        // An unplugged light fails, and is tried again when the backoff is up.
+        light.offline = true;
+        let now = midnight + chrono::Duration::days(1) + chrono::Duration::hours(7);
+        let update =
+            |light: &mut MockLight, memory: &mut LedMemory, health: &mut DeviceHealth, now| {
+                update_light(
+                    &config,
+                    &Schedules::new(&config, now),
+                    now,
+                    "mock",
+                    light,
+                    memory,
+                    health,
+                )
+            };
+        assert_eq!(
+            update(&mut light, &mut memory, &mut health, now),
+            Outcome::Failed
+        );
+        assert_eq!(
+            update(&mut light, &mut memory, &mut health, now),
+            Outcome::BackingOff
+        );
+        light.offline = false;
+        let later = now + chrono::Duration::minutes(10);
+        assert_eq!(
+            update(&mut light, &mut memory, &mut health, later),
+            Outcome::Ok
+        );
+        assert_eq!(health.state, HealthState::Online);
+        assert_eq!(
+            light.take_commands()[..2],
+            [MockCommand::Power(true), MockCommand::Preset(1)]
+        );
+    }
+}
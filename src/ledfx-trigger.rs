@@ -12,19 +12,26 @@ use std::thread;
 use std::time::Duration;
 use tray_icon::menu::MenuEvent;
 use tray_icon::TrayIconEvent;
-use util::led_set_preset;
 // use wled_json_api_library::structures::state::State;
 // use wled_json_api_library::wled::Wled;
+mod backend;
 mod config;
+mod cycle;
 mod ledfx;
+mod live;
 mod monitor;
+mod mqtt;
+mod simulate;
+mod solar;
 mod systray;
+mod tasmota;
 mod types;
 mod util;
-use crate::config::{calc_actual_config_file, load_config};
+use crate::config::{calc_actual_config_file, config_warnings, load_config};
 use crate::ledfx::playpause;
+use crate::mqtt::{MqttBridge, MqttCommand};
 use crate::types::*;
-use crate::util::{calc_led_state_scheduled, led_set_brightness, update_wled_cache};
+use crate::util::{is_static_device, mark_wled_gone, register_static_devices, update_wled_cache};
 
 const SERVICE_NAME: &str = "_wled._tcp.local.";
 // const NO_SCHEDULE: LEDScheduleSpec = LEDScheduleSpec::None;
@@ -32,6 +39,49 @@ const SERVICE_NAME: &str = "_wled._tcp.local.";
 fn main() {
     let args = Args::parse();
 
+    match &args.command {
+        Some(Command::Simulate {
+            target,
+            date,
+            step,
+            format,
+            output,
+        }) => {
+            let result = load_config(args.config_path.clone()).and_then(|config| {
+                simulate::run(
+                    &config,
+                    target,
+                    *date,
+                    (*step).into(),
+                    *format,
+                    output.clone(),
+                )
+            });
+            if let Err(err) = result {
+                eprintln!("Simulation failed: {:?}", err);
+                std::process::exit(-1);
+            }
+            return;
+        }
+        Some(Command::Check) => match load_config(args.config_path.clone()) {
+            Ok(config) => {
+                for warning in config_warnings(&config) {
+                    eprintln!("Warning: {}", warning);
+                }
+                println!(
+                    "{} is OK.",
+                    config.config_path.unwrap_or_default().display()
+                );
+                return;
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => (),
+    }
+
     let mut inotify = Inotify::init().expect("Failed to initialize inotify");
     let cfgfile = match args.config_path.clone() {
         Some(cfgpath) => cfgpath,
@@ -132,7 +182,12 @@ fn main() {
     info!("Loaded config: {:?}", &svc_config);
 
     util::cfg_logging(svc_config.loglevel, svc_config.logfile.clone());
+    for warning in config_warnings(&svc_config) {
+        warn!("{}", warning);
+    }
     let mdns = ServiceDaemon::new().expect("Failed to create daemon");
+    let mdns_receiver = mdns.browse(SERVICE_NAME).expect("Failed to browse");
+    let mut found_wled: HashMap<String, WLED> = HashMap::new();
     // let mut last_update = std::time::Instant::now();
 
     ///// Webserver
@@ -154,10 +209,18 @@ fn main() {
     }; // Note: Stream has to stay in scope or it gets collected and audio dies.
 
     let mut quiet_cycles: usize = 0;
+    // Whether we last told LedFx to play, so devices are handed over once.
+    let mut ledfx_showing = false;
     let mut inotify_buffer = [0u8; 4096];
-    let mut last_command_by_name: HashMap<String, (f32, Option<u16>, Option<bool>)> =
-        HashMap::new();
+    let mut http_client = util::http_client(&svc_config).expect("Failed to create HTTP client");
+    register_static_devices(&svc_config, &mut found_wled, &http_client);
+    let mut found_tasmota: HashMap<String, tasmota::Tasmota> = HashMap::new();
+    tasmota::register_tasmota(&svc_config, &mut found_tasmota, &http_client);
+    let mut tasmota_probe = tasmota::TasmotaProbe::start(&svc_config, &http_client);
+    let mut mqtt = svc_config.mqtt.as_ref().map(MqttBridge::connect);
     loop {
+        // Set when a reload is asked for over MQTT, whatever restart_on_cfg_change says.
+        let mut reload_requested = false;
         loop {
             info!("Checking inotify events...");
             if let Ok(events) = inotify.read_events(&mut inotify_buffer) {
@@ -171,7 +234,31 @@ fn main() {
                 }
             }
             // .read_events_blocking(&mut inotify_buffer)
-            let now = std::time::Instant::now();
+            for command in mqtt.iter().flat_map(|bridge| bridge.commands()) {
+                info!("MQTT command: {:?}", command);
+                match command {
+                    MqttCommand::LedFxEnabled(enabled) => {
+                        *ledfx_enabled.lock().expect("Failed to unlock") = enabled;
+                    }
+                    MqttCommand::Override { device, seconds } => {
+                        let now = svc_config.now().timestamp();
+                        if !mqtt::apply_override(
+                            &mut found_wled,
+                            &mut found_tasmota,
+                            &device,
+                            seconds,
+                            now,
+                        ) {
+                            warn!("No device called '{}' to override.", device);
+                        }
+                    }
+                    MqttCommand::Reload => reload_requested = true,
+                }
+            }
+            if reload_requested {
+                break;
+            }
+            let cycle_start = std::time::Instant::now();
             if playing_arc.load(Relaxed) {
                 debug!("arc says we are playing.");
                 quiet_cycles = 0;
@@ -190,8 +277,9 @@ fn main() {
                         "Got an enabled state change at {:?} to {:?}",
                         next_trigger, next_state
                     );
-                    if next_trigger.to_timestamp(svc_config.lat as f64, svc_config.lon as f64)
-                        < chrono::Local::now().timestamp() as u64
+                    let now = svc_config.now();
+                    if next_trigger.to_timestamp(&now, svc_config.lat as f64, svc_config.lon as f64)
+                        < now.timestamp() as u64
                     {
                         if let Some(enabled_state) = next_state {
                             *ledfx_enabled_locked = *enabled_state;
@@ -206,24 +294,84 @@ fn main() {
                 {
                     // Again, arbitrary
                     debug!("We have been quiet for a couple cycles.");
-                    playpause(baseurl.as_str(), true).unwrap_or_else(|_| {
-                        warn!("Failed to pause LEDFX!");
-                    });
+                    match playpause(baseurl.as_str(), true) {
+                        Ok(()) if ledfx_showing => {
+                            ledfx_showing = false;
+                            cycle::ledfx_stopped(&svc_config, &mut found_wled);
+                        }
+                        Ok(()) => (),
+                        Err(_) => warn!("Failed to pause LEDFX!"),
+                    }
                 } else {
                     debug!("We have NOT been quiet for a couple cycles. Showing LEDFX.");
-                    playpause(baseurl.as_str(), false).unwrap_or_else(|_| {
-                        warn!("Failed to pause LEDFX!");
-                    })
+                    if !ledfx_showing {
+                        cycle::ledfx_started(&svc_config, &mut found_wled);
+                    }
+                    match playpause(baseurl.as_str(), false) {
+                        Ok(()) => ledfx_showing = true,
+                        Err(_) => warn!("Failed to play LEDFX!"),
+                    }
                 }
             } else {
                 debug!("No LEDFX url found. Skipping updates.");
             }
 
-            let today: chrono::DateTime<chrono::Local> = chrono::Local::now();
-            let mut leds_ok: usize = 0;
-            let mut leds_noconfig: usize = 0;
-            let leds_ignore: usize = 0;
-            let mut leds_err: usize = 0;
+            let today = svc_config.now();
+
+            // Pick up any WLEDs that have shown up, moved or left since last cycle.
+            while let Ok(event) = mdns_receiver.try_recv() {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => {
+                        debug!("Resolved mDNS service: {}", info.get_fullname());
+                        if is_static_device(&svc_config, &found_wled, &info) {
+                            debug!("{} is configured by hand, ignoring.", info.get_fullname());
+                            continue;
+                        }
+                        update_wled_cache(&info, &svc_config, &mut found_wled, &http_client)
+                            .unwrap_or_else(|err| {
+                                warn!("Failed to register WLED: {:?}", err);
+                            });
+                    }
+                    ServiceEvent::ServiceRemoved(_, full_name) => {
+                        // Goodbyes and expired records both end up here.
+                        if !svc_config.devices.contains_key(&full_name) {
+                            mark_wled_gone(&full_name, &mut found_wled);
+                        }
+                    }
+                    other_event => {
+                        trace!("Ignoring mDNS event: {:?}", &other_event);
+                    }
+                }
+            }
+
+            tasmota_probe.collect(&svc_config, &mut found_tasmota, &http_client);
+            let schedules = util::Schedules::new(&svc_config, today);
+            let mut counts = cycle::run_cycle(&svc_config, &schedules, today, &mut found_wled);
+            counts.merge(cycle::run_tasmota_cycle(
+                &svc_config,
+                &schedules,
+                today,
+                &mut found_tasmota,
+            ));
+            let elapsed = cycle_start.elapsed();
+            info!(
+                "Cycle complete in {:.1?}: {} ok, {} failed, {} backing off, {} gone, {} unconfigured, {} unscheduled, {} held.",
+                elapsed,
+                counts.ok,
+                counts.failed,
+                counts.backing_off,
+                counts.gone,
+                counts.noconfig,
+                counts.unscheduled,
+                counts.held
+            );
+            if let Some(bridge) = &mqtt {
+                bridge.publish_flags(
+                    *ledfx_enabled.lock().expect("Failed to unlock"),
+                    playing_arc.load(Relaxed),
+                );
+                bridge.publish_devices(&svc_config, &schedules, today, &found_wled, &found_tasmota);
+            }
             {
                 // Locking die arc...
                 let die = die_arc.lock().unwrap();
@@ -233,27 +381,68 @@ fn main() {
             } // Locking die arc...
 
             //std::thread::sleep(Duration::from_secs(10));
-            std::thread::sleep(Duration::from_secs_f64(svc_config.cycle_seconds));
+            // Take the time spent updating out of the wait, so cycles stay on cadence.
+            std::thread::sleep(
+                Duration::from_secs_f64(svc_config.cycle_seconds).saturating_sub(elapsed),
+            );
         } // Loop wleds
-        match svc_config.restart_on_cfg_change {
+        let action = if reload_requested {
+            CfgChangeAction::Reload
+        } else {
+            svc_config.restart_on_cfg_change.clone()
+        };
+        match action {
             CfgChangeAction::No => (),
             CfgChangeAction::Exit => {
-                info!("Exiting due to a config change.");
-                std::process::exit(0);
+                // Whatever restarts us would just fail on a broken config.
+                if let Err(err) = load_config(args.config_path.clone()) {
+                    error!("Not exiting, the new config is broken: {}", err);
+                } else {
+                    info!("Exiting due to a config change.");
+                    std::process::exit(0);
+                }
             }
             CfgChangeAction::Reload => {
                 info!("Reloading due to a config change.");
                 let old_loglevel = svc_config.loglevel;
                 let old_logfile = svc_config.logfile.clone();
                 let old_tray_icon = svc_config.tray_icon;
+                let old_mqtt = svc_config.mqtt.clone();
                 svc_config = match load_config(args.config_path.clone()) {
                     Ok(config) => config,
                     Err(err) => {
-                        // panic!("Failed to load config: {:?}", err),
-                        eprintln!("Failed to load config: {:?}", err);
-                        std::process::exit(-1);
+                        // Keep running on the last good config rather than
+                        // dying (or going dark) because of a half-saved edit.
+                        error!("Not reloading, the new config is broken: {}", err);
+                        continue;
                     }
                 };
+                for warning in config_warnings(&svc_config) {
+                    warn!("{}", warning);
+                }
+                match util::http_client(&svc_config) {
+                    Ok(client) => {
+                        http_client = client;
+                        for wled in found_wled.values_mut() {
+                            wled.device.client = http_client.clone();
+                        }
+                        for device in found_tasmota.values_mut() {
+                            device.client = http_client.clone();
+                        }
+                    }
+                    Err(err) => error!("Keeping the old HTTP timeouts: {:?}", err),
+                }
+                register_static_devices(&svc_config, &mut found_wled, &http_client);
+                tasmota::register_tasmota(&svc_config, &mut found_tasmota, &http_client);
+                // Probing takes a while, so only start again if what to probe changed.
+                if tasmota_probe.specs() != svc_config.tasmota_probe.as_slice() {
+                    tasmota_probe = tasmota::TasmotaProbe::start(&svc_config, &http_client);
+                }
+                if old_mqtt != svc_config.mqtt {
+                    // Drop the old connection first, so its client ID is free.
+                    drop(mqtt.take());
+                    mqtt = svc_config.mqtt.as_ref().map(MqttBridge::connect);
+                }
                 if old_loglevel != svc_config.loglevel
                     || old_logfile != svc_config.logfile
                     || old_tray_icon != svc_config.tray_icon
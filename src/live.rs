@@ -0,0 +1,194 @@
+/// Keeps a WLED's state current over its WebSocket, so the scheduler can
+/// see changes made elsewhere without asking for them.
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use reqwest::Url;
+use serde::Deserialize;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tungstenite::Message;
+use wled_json_api_library::structures::state::State;
+
+/// How often the reader wakes up to see if it should stop.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest wait between attempts to reconnect.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// WLED pushes the whole state (and info) on connect and after every change.
+#[derive(Deserialize)]
+struct LiveMessage {
+    state: Option<State>,
+}
+
+/// A WebSocket reader thread for one WLED. The thread stops when this is
+/// dropped.
+#[derive(Debug)]
+pub struct LiveState {
+    latest: Arc<Mutex<Option<State>>>,
+    connected: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+}
+
+impl LiveState {
+    /// Starts following the WLED `name`, whose JSON API is at `url`.
+    pub fn spawn(name: &str, url: &Url, connect_timeout: Duration) -> Result<LiveState> {
+        let address = url
+            .socket_addrs(|| Some(80))?
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("No address for {}", url))?;
+        let mut ws_url = url.clone();
+        ws_url
+            .set_scheme("ws")
+            .map_err(|_| anyhow!("Can't make a WebSocket URL from {}", url))?;
+        ws_url.set_path("/ws");
+        let live = LiveState {
+            latest: Arc::new(Mutex::new(None)),
+            connected: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        let name = name.to_string();
+        let latest = live.latest.clone();
+        let connected = live.connected.clone();
+        let stop = live.stop.clone();
+        std::thread::Builder::new()
+            .name(format!("live {}", name))
+            .spawn(move || {
+                let mut delay = POLL_INTERVAL;
+                while !stop.load(Ordering::Relaxed) {
+                    match follow(
+                        &ws_url,
+                        address,
+                        connect_timeout,
+                        &latest,
+                        &connected,
+                        &stop,
+                    ) {
+                        Ok(()) => break,
+                        Err(err) => {
+                            if connected.swap(false, Ordering::Relaxed) {
+                                info!("Lost live connection to WLED '{}': {}", name, err);
+                                delay = POLL_INTERVAL;
+                            } else {
+                                debug!("No live connection to WLED '{}': {}", name, err);
+                            }
+                        }
+                    }
+                    // Wait out the delay a little at a time, so a stop isn't held up.
+                    let mut waited = Duration::ZERO;
+                    while waited < delay && !stop.load(Ordering::Relaxed) {
+                        std::thread::sleep(POLL_INTERVAL);
+                        waited += POLL_INTERVAL;
+                    }
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            })?;
+        Ok(live)
+    }
+
+    /// The state the WLED last pushed, if it has pushed one since the last call.
+    pub fn take(&self) -> Option<State> {
+        self.latest.lock().unwrap().take()
+    }
+
+    /// Whether the WebSocket is up, so the state we have is current.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for LiveState {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Connects and reads state updates until told to stop (Ok) or the
+/// connection fails (Err).
+fn follow(
+    url: &Url,
+    address: SocketAddr,
+    connect_timeout: Duration,
+    latest: &Mutex<Option<State>>,
+    connected: &AtomicBool,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let stream = TcpStream::connect_timeout(&address, connect_timeout)?;
+    stream.set_read_timeout(Some(connect_timeout))?;
+    let (mut socket, _) = tungstenite::client(url.as_str(), stream)
+        .map_err(|err| anyhow!("WebSocket handshake failed: {}", err))?;
+    socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    if !connected.swap(true, Ordering::Relaxed) {
+        info!("Live connection to {} is up.", url);
+    }
+    while !stop.load(Ordering::Relaxed) {
+        match socket.read() {
+            Ok(Message::Text(text)) => match serde_json::from_str::<LiveMessage>(&text) {
+                Ok(LiveMessage { state: Some(state) }) => {
+                    *latest.lock().unwrap() = Some(state);
+                }
+                Ok(_) => (),
+                Err(err) => debug!("Ignoring message from {}: {}", url, err),
+            },
+            Ok(Message::Close(_)) => return Err(anyhow!("Closed by the WLED")),
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    socket.close(None).ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_live_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = std::thread::spawn(move || {
+            // The first connection is dropped straight away, so the second
+            // one shows the reader reconnects.
+            for (attempt, bri) in [(0, 10), (1, 20)] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut socket = tungstenite::accept(stream).unwrap();
+                socket
+                    .send(Message::Text(format!(
+                        r#"{{"state": {{"on": true, "bri": {}}}, "info": {{}}}}"#,
+                        bri
+                    )))
+                    .unwrap();
+                if attempt == 1 {
+                    // Hold the connection until the client goes away.
+                    while socket.read().is_ok() {}
+                }
+            }
+        });
+
+        let live = LiveState::spawn("test", &url, Duration::from_secs(1)).unwrap();
+        let mut bris = Vec::new();
+        for _ in 0..100 {
+            if let Some(state) = live.take() {
+                bris.push(state.bri.unwrap());
+                if state.bri == Some(20) {
+                    break;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(bris.last(), Some(&20));
+        assert!(live.is_connected());
+        assert!(live.take().is_none());
+        drop(live);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+/// Publishes what the daemon is doing to an MQTT broker, and passes on the
+/// commands sent back through it.
+///
+/// Under `topic_prefix` (here `ledfx-trigger`), retained:
+///   ledfx-trigger/status                    "online" or "offline"
+///   ledfx-trigger/ledfx/enabled             "true" or "false"
+///   ledfx-trigger/audio/playing             "true" or "false"
+///   ledfx-trigger/devices/<name>/state      what the schedule calls for (JSON)
+///   ledfx-trigger/devices/<name>/health     how the device is answering (JSON)
+/// and listened to:
+///   ledfx-trigger/ledfx/enabled/set         "true"/"on" or "false"/"off"
+///   ledfx-trigger/devices/<name>/override/set
+///                                           seconds to leave it alone, or
+///                                           "off" to put it back on schedule
+///   ledfx-trigger/reload                    reload the config file
+use crate::tasmota::Tasmota;
+use crate::types::*;
+use crate::util::{calc_led_bri, Schedules};
+use chrono::DateTime;
+use log::{debug, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::time::Duration;
+use wled_json_api_library::structures::info::Info;
+
+/// How long to wait before trying the broker again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Messages queued for the broker before we start dropping them.
+const QUEUE_SIZE: usize = 256;
+
+/// Something asked of us over MQTT.
+#[derive(Debug, PartialEq)]
+pub enum MqttCommand {
+    /// Turn LedFx on or off, as the tray menu does.
+    LedFxEnabled(bool),
+    /// Leave the device with this topic name alone for a while, or put it
+    /// back on schedule (`None`).
+    Override {
+        device: String,
+        seconds: Option<u64>,
+    },
+    Reload,
+}
+
+/// What's published for each device.
+#[derive(Serialize)]
+struct DeviceState<'a> {
+    /// The state the schedule calls for right now.
+    schedule: Option<&'a LEDState>,
+    /// That brightness, scaled for this device (0-255).
+    bri: Option<u8>,
+    /// When a hold (by hand or over MQTT) runs out, as a unix timestamp.
+    held_until: Option<i64>,
+}
+
+/// A connection to the broker, kept up by a thread of its own. It's closed
+/// when this is dropped.
+pub struct MqttBridge {
+    client: Client,
+    prefix: String,
+    connected: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    commands: Receiver<MqttCommand>,
+}
+
+impl MqttBridge {
+    /// Starts connecting to the broker in `config`. Until it answers (and
+    /// whenever it goes away) publishing is skipped.
+    pub fn connect(config: &MqttConfig) -> MqttBridge {
+        let prefix = config.topic_prefix.trim_end_matches('/').to_string();
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options
+            .set_keep_alive(Duration::from_secs(30))
+            .set_last_will(LastWill::new(
+                format!("{}/status", prefix),
+                "offline",
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, QUEUE_SIZE);
+        let (sender, commands) = channel();
+        let bridge = MqttBridge {
+            client: client.clone(),
+            prefix: prefix.clone(),
+            connected: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
+            commands,
+        };
+        let connected = bridge.connected.clone();
+        let stop = bridge.stop.clone();
+        let broker = format!("{}:{}", config.host, config.port);
+        std::thread::Builder::new()
+            .name("mqtt".to_string())
+            .spawn(move || {
+                for event in connection.iter() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    match event {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            info!("Connected to MQTT broker {}.", broker);
+                            // The session is clean, so subscribe again every time.
+                            for topic in [
+                                format!("{}/ledfx/enabled/set", prefix),
+                                format!("{}/devices/+/override/set", prefix),
+                                format!("{}/reload", prefix),
+                            ] {
+                                if let Err(err) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                                    warn!("Couldn't subscribe on MQTT: {}", err);
+                                }
+                            }
+                            client
+                                .try_publish(
+                                    format!("{}/status", prefix),
+                                    QoS::AtLeastOnce,
+                                    true,
+                                    "online",
+                                )
+                                .ok();
+                            connected.store(true, Ordering::Relaxed);
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            match parse_command(&prefix, &publish.topic, &publish.payload) {
+                                Some(command) => {
+                                    if sender.send(command).is_err() {
+                                        break;
+                                    }
+                                }
+                                None => debug!("Ignoring MQTT message on {}", publish.topic),
+                            }
+                        }
+                        Ok(_) => (),
+                        Err(err) => {
+                            if connected.swap(false, Ordering::Relaxed) {
+                                warn!("Lost MQTT broker {}: {}", broker, err);
+                            } else {
+                                debug!("No MQTT broker at {}: {}", broker, err);
+                            }
+                            std::thread::sleep(RECONNECT_DELAY);
+                        }
+                    }
+                }
+            })
+            .expect("Failed to start the MQTT thread");
+        bridge
+    }
+
+    /// Commands that have come in since the last call.
+    pub fn commands(&self) -> impl Iterator<Item = MqttCommand> + '_ {
+        self.commands.try_iter()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn publish(&self, topic: &str, payload: impl Into<Vec<u8>>) {
+        if !self.is_connected() {
+            return;
+        }
+        if let Err(err) = self.client.try_publish(
+            format!("{}/{}", self.prefix, topic),
+            QoS::AtMostOnce,
+            true,
+            payload,
+        ) {
+            debug!("Couldn't publish {} on MQTT: {}", topic, err);
+        }
+    }
+
+    pub fn publish_flags(&self, ledfx_enabled: bool, audio_playing: bool) {
+        self.publish("ledfx/enabled", ledfx_enabled.to_string());
+        self.publish("audio/playing", audio_playing.to_string());
+    }
+
+    /// Publishes the scheduled state and health of every device.
+    pub fn publish_devices(
+        &self,
+        config: &Config,
+        schedules: &Schedules,
+        now: DateTime<chrono_tz::Tz>,
+        found_wled: &HashMap<String, WLED>,
+        found_tasmota: &HashMap<String, Tasmota>,
+    ) {
+        let devices = found_wled
+            .iter()
+            .map(|(name, wled)| {
+                (
+                    name,
+                    wled.device.info.as_ref(),
+                    wled.memory.held_until,
+                    &wled.health,
+                )
+            })
+            .chain(
+                found_tasmota
+                    .iter()
+                    .map(|(name, device)| (name, None, device.memory.held_until, &device.health)),
+            );
+        for (name, info, held_until, health) in devices {
+            let scheduled = scheduled_state(config, schedules, now.timestamp(), name, info);
+            let state = DeviceState {
+                schedule: scheduled.as_ref().map(|(state, _)| state),
+                bri: scheduled.as_ref().map(|(_, bri)| *bri),
+                held_until,
+            };
+            let topic = format!("devices/{}", topic_name(name));
+            match (serde_json::to_string(&state), serde_json::to_string(health)) {
+                (Ok(state), Ok(health)) => {
+                    self.publish(&format!("{}/state", topic), state);
+                    self.publish(&format!("{}/health", topic), health);
+                }
+                (Err(err), _) | (_, Err(err)) => {
+                    warn!("Couldn't describe '{}' for MQTT: {}", name, err)
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.is_connected() {
+            self.client
+                .try_publish(
+                    format!("{}/status", self.prefix),
+                    QoS::AtLeastOnce,
+                    true,
+                    "offline",
+                )
+                .ok();
+        }
+        self.client.try_disconnect().ok();
+    }
+}
+
+/// A device name as it appears in topics, with MQTT's special characters
+/// swapped out.
+pub fn topic_name(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+fn parse_bool(payload: &str) -> Option<bool> {
+    match payload.trim().to_ascii_lowercase().as_str() {
+        "true" | "on" | "1" | "enable" => Some(true),
+        "false" | "off" | "0" | "disable" => Some(false),
+        _ => None,
+    }
+}
+
+/// Turns a message on one of our command topics into a command.
+pub fn parse_command(prefix: &str, topic: &str, payload: &[u8]) -> Option<MqttCommand> {
+    let payload = std::str::from_utf8(payload).ok()?.trim();
+    let topic = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    if topic == "reload" {
+        return Some(MqttCommand::Reload);
+    }
+    if topic == "ledfx/enabled/set" {
+        return parse_bool(payload).map(MqttCommand::LedFxEnabled);
+    }
+    let device = topic
+        .strip_prefix("devices/")?
+        .strip_suffix("/override/set")?;
+    let seconds = match payload.parse::<u64>() {
+        Ok(0) => None,
+        Ok(seconds) => Some(seconds),
+        Err(_) if parse_bool(payload) == Some(false) || payload.eq_ignore_ascii_case("resume") => {
+            None
+        }
+        Err(_) => return None,
+    };
+    Some(MqttCommand::Override {
+        device: device.to_string(),
+        seconds,
+    })
+}
+
+/// Holds (or releases) the WLED or Tasmota whose topic name is `device`.
+/// Says whether there was one.
+pub fn apply_override(
+    found_wled: &mut HashMap<String, WLED>,
+    found_tasmota: &mut HashMap<String, Tasmota>,
+    device: &str,
+    seconds: Option<u64>,
+    now: i64,
+) -> bool {
+    let Some((name, memory)) = found_wled
+        .iter_mut()
+        .map(|(name, wled)| (name, &mut wled.memory))
+        .chain(
+            found_tasmota
+                .iter_mut()
+                .map(|(name, device)| (name, &mut device.memory)),
+        )
+        .find(|(name, _)| topic_name(name) == device)
+    else {
+        return false;
+    };
+    match seconds {
+        Some(seconds) => {
+            info!(
+                "Leaving '{}' alone for {}s, as asked over MQTT.",
+                name, seconds
+            );
+            memory.held_until = Some(now + seconds as i64);
+        }
+        None => {
+            info!("Putting '{}' back on schedule, as asked over MQTT.", name);
+            memory.held_until = None;
+            memory.forget_sent();
+        }
+    }
+    true
+}
+
+/// The state `name`'s schedule calls for at `now`, and the brightness that
+/// comes to on it.
+fn scheduled_state(
+    config: &Config,
+    schedules: &Schedules,
+    now: i64,
+    name: &str,
+    info: Option<&Info>,
+) -> Option<(LEDState, u8)> {
+    let led_config = config.led_config(name, info)?;
+    let state = schedules.get(&led_config.schedule)?.state_at(now)?;
+    let bri = calc_led_bri(led_config, state.bri);
+    Some((state, bri))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_override() {
+        let client = crate::util::http_client(&Config::default()).unwrap();
+        let url = reqwest::Url::parse("http://127.0.0.1:1/").unwrap();
+        let mut found_wled = HashMap::new();
+        let mut found_tasmota = HashMap::from([(
+            "porch-bulb".to_string(),
+            Tasmota::new("porch-bulb", url, &client),
+        )]);
+        assert!(apply_override(
+            &mut found_wled,
+            &mut found_tasmota,
+            "porch-bulb",
+            Some(60),
+            1000
+        ));
+        assert_eq!(found_tasmota["porch-bulb"].memory.held_until, Some(1060));
+        assert!(apply_override(
+            &mut found_wled,
+            &mut found_tasmota,
+            "porch-bulb",
+            None,
+            1000
+        ));
+        assert_eq!(found_tasmota["porch-bulb"].memory.held_until, None);
+        assert!(!apply_override(
+            &mut found_wled,
+            &mut found_tasmota,
+            "nope",
+            None,
+            1000
+        ));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let parse =
+            |topic: &str, payload: &str| parse_command("home/leds", topic, payload.as_bytes());
+        assert_eq!(parse("home/leds/reload", ""), Some(MqttCommand::Reload));
+        assert_eq!(
+            parse("home/leds/ledfx/enabled/set", "OFF"),
+            Some(MqttCommand::LedFxEnabled(false))
+        );
+        assert_eq!(
+            parse("home/leds/ledfx/enabled/set", "true"),
+            Some(MqttCommand::LedFxEnabled(true))
+        );
+        assert_eq!(parse("home/leds/ledfx/enabled/set", "maybe"), None);
+        assert_eq!(
+            parse("home/leds/devices/wled-kitchen/override/set", "3600"),
+            Some(MqttCommand::Override {
+                device: "wled-kitchen".to_string(),
+                seconds: Some(3600),
+            })
+        );
+        assert_eq!(
+            parse("home/leds/devices/wled-kitchen/override/set", "resume"),
+            Some(MqttCommand::Override {
+                device: "wled-kitchen".to_string(),
+                seconds: None,
+            })
+        );
+        assert_eq!(
+            parse("home/leds/devices/wled-kitchen/override/set", "-5"),
+            None
+        );
+        assert_eq!(parse("home/ledsx/reload", ""), None);
+        assert_eq!(parse("other/reload", ""), None);
+        assert_eq!(topic_name("a/b+c#d"), "a_b_c_d");
+    }
+
+    /// Needs a broker, e.g. `mosquitto -p 1883`. Set MQTT_TEST_BROKER to use
+    /// another host than localhost, and run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_mqtt_bridge() {
+        let host = std::env::var("MQTT_TEST_BROKER").unwrap_or("localhost".to_string());
+        let prefix = format!("ledfx-trigger-test-{}", std::process::id());
+        let bridge = MqttBridge::connect(&MqttConfig {
+            host: host.clone(),
+            port: 1883,
+            client_id: format!("{}-bridge", prefix),
+            username: None,
+            password: None,
+            topic_prefix: prefix.clone(),
+        });
+        let (client, mut connection) =
+            Client::new(MqttOptions::new(format!("{}-test", prefix), host, 1883), 10);
+        client
+            .subscribe(format!("{}/#", prefix), QoS::AtLeastOnce)
+            .unwrap();
+        let messages = std::thread::spawn(move || {
+            let mut messages = HashMap::new();
+            for event in connection.iter() {
+                if let Ok(Event::Incoming(Packet::Publish(publish))) = event {
+                    messages.insert(
+                        publish.topic.clone(),
+                        String::from_utf8_lossy(&publish.payload).to_string(),
+                    );
+                    if publish.topic.ends_with("/audio/playing") {
+                        break;
+                    }
+                }
+            }
+            messages
+        });
+
+        for _ in 0..50 {
+            if bridge.is_connected() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(bridge.is_connected());
+        client
+            .publish(
+                format!("{}/ledfx/enabled/set", prefix),
+                QoS::AtLeastOnce,
+                false,
+                "off",
+            )
+            .unwrap();
+        let mut commands = Vec::new();
+        for _ in 0..50 {
+            commands.extend(bridge.commands());
+            if !commands.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(commands, vec![MqttCommand::LedFxEnabled(false)]);
+
+        bridge.publish_flags(false, true);
+        let messages = messages.join().unwrap();
+        assert_eq!(messages[&format!("{}/status", prefix)], "online");
+        assert_eq!(messages[&format!("{}/ledfx/enabled", prefix)], "false");
+        assert_eq!(messages[&format!("{}/audio/playing", prefix)], "true");
+    }
+}
@@ -0,0 +1,359 @@
+/// Dry-runs a schedule over a day so it can be checked before deploying.
+use crate::types::*;
+use crate::util::{calc_led_bri, ResolvedSchedule};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime};
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+pub struct SimRow {
+    pub time: DateTime<chrono_tz::Tz>,
+    /// Scheduled brightness, 0.0-1.0.
+    pub bri_pc: f32,
+    /// What would be sent to the WLED, if simulating a configured LED.
+    pub bri: Option<u8>,
+    pub preset: Option<u16>,
+    pub power: Option<bool>,
+    pub cct: Option<u16>,
+    pub color: Option<Vec<u8>>,
+    pub effect: Option<NameOrId>,
+    pub palette: Option<NameOrId>,
+    pub speed: Option<u8>,
+    pub intensity: Option<u8>,
+    pub playlist: Option<PlaylistState>,
+    pub nightlight: Option<NightlightState>,
+}
+
+/// Steps through `date` (local midnight to midnight in the configured zone)
+/// and records the scheduled state at each step. `target` is either a
+/// schedule name or an LED name (from `config.leds`, or matching a group by
+/// name); schedule names win.
+pub fn simulate_day(
+    config: &Config,
+    target: &str,
+    date: NaiveDate,
+    step: Duration,
+) -> Result<Vec<SimRow>> {
+    // Schedule names are looked up first, so a catch-all group can't take
+    // them for an LED name.
+    let (schedule, led_config) = match config.schedule.get(target) {
+        Some(schedule) => (schedule, None),
+        None => {
+            let led_config = config.led_config(target, None).ok_or_else(|| {
+                anyhow!(
+                    "'{}' is neither a configured LED nor a schedule name",
+                    target
+                )
+            })?;
+            (
+                config.led_schedule(&led_config.schedule).ok_or_else(|| {
+                    anyhow!(
+                        "LED '{}' has no usable schedule ({:?})",
+                        target,
+                        led_config.schedule
+                    )
+                })?,
+                Some(led_config),
+            )
+        }
+    };
+    let step = chrono::Duration::from_std(step)?;
+    if step <= chrono::Duration::zero() {
+        return Err(anyhow!("Simulation step must be greater than zero"));
+    }
+
+    let tz = config.zone();
+    let start = local_to_timestamp(&tz, date.and_time(NaiveTime::MIN));
+    let end = local_to_timestamp(
+        &tz,
+        (date + chrono::Duration::days(1)).and_time(NaiveTime::MIN),
+    );
+    let mut time = DateTime::from_timestamp(start, 0)
+        .ok_or_else(|| anyhow!("Date out of range: {}", date))?
+        .with_timezone(&tz);
+    let resolved = ResolvedSchedule::new(schedule, date, &tz, config.lat as f64, config.lon as f64);
+    let mut rows = Vec::new();
+    while time.timestamp() < end {
+        let state = resolved.state_at(time.timestamp()).ok_or_else(|| {
+            anyhow!(
+                "The schedule has no brightness keyframes around {}",
+                time.to_rfc3339()
+            )
+        })?;
+        rows.push(SimRow {
+            time,
+            bri_pc: state.bri,
+            bri: led_config.map(|led_config| calc_led_bri(led_config, state.bri)),
+            preset: state.preset,
+            power: state.power,
+            cct: state.cct,
+            color: state.color,
+            effect: state.effect,
+            palette: state.palette,
+            speed: state.speed,
+            intensity: state.intensity,
+            playlist: state.playlist,
+            nightlight: state.nightlight,
+        });
+        time += step;
+    }
+    Ok(rows)
+}
+
+fn opt_to_string<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Colors print as hex, e.g. "#ff8c00" or "#ff8c0020" with a white channel.
+fn color_to_string(color: &Option<Vec<u8>>) -> String {
+    match color {
+        Some(color) => color.iter().fold("#".to_string(), |out, channel| {
+            out + &format!("{:02x}", channel)
+        }),
+        None => String::new(),
+    }
+}
+
+/// Quotes a CSV field if it needs it, doubling any quotes inside. Effect and
+/// palette names come from the config, so they may hold commas.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Column names, and their widths in the table format.
+const COLUMNS: [(&str, usize); 13] = [
+    ("time", 25),
+    ("bri_pc", 6),
+    ("bri", 4),
+    ("preset", 6),
+    ("power", 5),
+    ("cct", 5),
+    ("color", 9),
+    ("effect", 12),
+    ("palette", 12),
+    ("speed", 5),
+    ("intensity", 9),
+    ("playlist", 8),
+    ("nightlight", 10),
+];
+
+fn row_columns(row: &SimRow) -> [String; 13] {
+    [
+        row.time.to_rfc3339(),
+        format!("{:.4}", row.bri_pc),
+        opt_to_string(&row.bri),
+        opt_to_string(&row.preset),
+        opt_to_string(&row.power),
+        opt_to_string(&row.cct),
+        color_to_string(&row.color),
+        opt_to_string(&row.effect),
+        opt_to_string(&row.palette),
+        opt_to_string(&row.speed),
+        opt_to_string(&row.intensity),
+        match row.playlist {
+            Some(PlaylistState::Running(id)) => id.to_string(),
+            Some(PlaylistState::Stopped) => "stop".to_string(),
+            None => String::new(),
+        },
+        // Shown as the brightness it's heading for, e.g. "fade>0".
+        match row.nightlight {
+            Some(nightlight) => format!(
+                "{}>{}",
+                format!("{:?}", nightlight.mode).to_lowercase(),
+                nightlight.target_bri
+            ),
+            None => String::new(),
+        },
+    ]
+}
+
+pub fn write_rows(rows: &[SimRow], format: SimFormat, out: &mut dyn Write) -> Result<()> {
+    match format {
+        SimFormat::Table => {
+            let header: Vec<String> = COLUMNS
+                .iter()
+                .enumerate()
+                .map(|(idx, (name, width))| match idx {
+                    0 => format!("{:<width$}", name),
+                    _ => format!("{:>width$}", name),
+                })
+                .collect();
+            writeln!(out, "{}", header.join(" "))?;
+            for row in rows {
+                let line: Vec<String> = row_columns(row)
+                    .iter()
+                    .zip(COLUMNS.iter())
+                    .enumerate()
+                    .map(|(idx, (value, (_, width)))| match idx {
+                        0 => format!("{:<width$}", value),
+                        _ => format!("{:>width$}", value),
+                    })
+                    .collect();
+                writeln!(out, "{}", line.join(" "))?;
+            }
+        }
+        SimFormat::Csv => {
+            let header: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+            writeln!(out, "{}", header.join(","))?;
+            for row in rows {
+                let line: Vec<String> = row_columns(row)
+                    .iter()
+                    .map(|value| csv_field(value))
+                    .collect();
+                writeln!(out, "{}", line.join(","))?;
+            }
+        }
+        SimFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, rows)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Entry point for the `simulate` subcommand.
+pub fn run(
+    config: &Config,
+    target: &str,
+    date: Option<NaiveDate>,
+    step: Duration,
+    format: SimFormat,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let date = date.unwrap_or_else(|| config.now().date_naive());
+    let rows = simulate_day(config, target, date, step)?;
+    match output {
+        Some(path) => {
+            let mut file = std::fs::File::create(&path)?;
+            write_rows(&rows, format, &mut file)
+        }
+        None => write_rows(&rows, format, &mut std::io::stdout().lock()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn test_config() -> Config {
+        Config {
+            lat: 49.,
+            lon: -124.,
+            timezone: Some("America/Vancouver".to_string()),
+            leds: HashMap::from([(
+                "wled-test._wled._tcp.local.".to_string(),
+                LEDBrightnessConfig {
+                    schedule: LEDScheduleSpec::Default,
+                    min_bri: 10,
+                    max_bri: 110,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulate_day() {
+        let config = test_config();
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let rows = simulate_day(
+            &config,
+            "wled-test._wled._tcp.local.",
+            date,
+            Duration::from_secs(15 * 60),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 96);
+        assert_eq!(rows[0].time.to_rfc3339(), "2024-06-21T00:00:00-07:00");
+        // The default schedule is 1.0 at sunrise, 0.2 at sunset.
+        let noon = &rows[48];
+        assert!(noon.bri_pc > 0.2 && noon.bri_pc < 1.0);
+        assert!(rows.iter().all(|row| row.bri.unwrap() >= 10));
+
+        let rows = simulate_day(&config, "default", date, Duration::from_secs(3600)).unwrap();
+        assert_eq!(rows.len(), 24);
+        assert!(rows.iter().all(|row| row.bri.is_none()));
+
+        // The spring-forward day is an hour short.
+        let date = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        let rows = simulate_day(&config, "default", date, Duration::from_secs(3600)).unwrap();
+        assert_eq!(rows.len(), 23);
+
+        assert!(simulate_day(&config, "nope", date, Duration::from_secs(60)).is_err());
+
+        // A group that takes every LED name doesn't take schedule names.
+        let mut config = config;
+        config.groups.insert(
+            "everything".to_string(),
+            LEDGroup {
+                members: GroupMatch::Glob("*".to_string()),
+                priority: 0,
+                config: LEDBrightnessConfig {
+                    schedule: LEDScheduleSpec::None,
+                    ..Default::default()
+                },
+            },
+        );
+        let rows = simulate_day(&config, "default", date, Duration::from_secs(3600)).unwrap();
+        assert!(rows.iter().all(|row| row.bri.is_none()));
+        assert!(simulate_day(&config, "nope", date, Duration::from_secs(60)).is_err());
+        assert!(simulate_day(&config, "default", date, Duration::from_secs(0)).is_err());
+    }
+
+    #[test]
+    fn test_write_rows() {
+        let config = test_config();
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let rows = simulate_day(
+            &config,
+            "wled-test._wled._tcp.local.",
+            date,
+            Duration::from_secs(6 * 3600),
+        )
+        .unwrap();
+        let mut csv = Vec::new();
+        write_rows(&rows, SimFormat::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("time,bri_pc,bri,preset,power,cct,color,effect,palette,speed,intensity,playlist,nightlight")
+        );
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                "2024-06-21T00:00:00-07:00,{:.4},{},,,,,,,,,,",
+                rows[0].bri_pc,
+                rows[0].bri.unwrap()
+            )
+        );
+        assert_eq!(csv.lines().count(), 5);
+
+        let mut rows = rows;
+        rows[0].effect = Some(NameOrId::Name("Fire, \"2012\"".to_string()));
+        let mut csv = Vec::new();
+        write_rows(&rows[..1], SimFormat::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(",,,,\"Fire, \"\"2012\"\"\",,,,,"));
+
+        let mut json = Vec::new();
+        write_rows(&rows, SimFormat::Json, &mut json).unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed.as_array().unwrap().len(), 4);
+        assert_eq!(parsed[0]["bri"], rows[0].bri.unwrap());
+        assert!(parsed[0]["preset"].is_null());
+    }
+}
@@ -0,0 +1,130 @@
+/// Sun position maths for the sun-relative schedule times. This is the same
+/// sunrise equation the `sunrise` crate uses, except the elevation angle is a
+/// parameter instead of being fixed at the horizon, so we can also find
+/// twilight and arbitrary "sun at N degrees" times.
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+
+const DEGREE: f64 = std::f64::consts::PI / 180.;
+const SECONDS_IN_A_DAY: f64 = 86400.;
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2440587.5;
+const J2000: f64 = 2451545.;
+
+pub const CIVIL_TWILIGHT: f64 = -6.;
+pub const NAUTICAL_TWILIGHT: f64 = -12.;
+pub const ASTRONOMICAL_TWILIGHT: f64 = -18.;
+
+fn unix_to_julian(timestamp: i64) -> f64 {
+    timestamp as f64 / SECONDS_IN_A_DAY + UNIX_EPOCH_JULIAN_DAY
+}
+
+fn julian_to_unix(day: f64) -> i64 {
+    ((day - UNIX_EPOCH_JULIAN_DAY) * SECONDS_IN_A_DAY) as i64
+}
+
+/// Returns the julian day of solar transit (local noon) and the sun's
+/// declination in degrees for the given date and longitude.
+fn transit_and_declination(lon: f64, date: NaiveDate) -> (f64, f64) {
+    let noon = Utc
+        .with_ymd_and_hms(date.year(), date.month(), date.day(), 12, 0, 0)
+        .earliest()
+        .expect("Noon UTC always exists.");
+    let day = unix_to_julian(noon.timestamp()) - lon / 360.;
+
+    let anomaly = (357.5291 + 0.98560028 * (day - J2000)).rem_euclid(360.);
+    let anomaly_rad = anomaly * DEGREE;
+    let center = 1.9148 * anomaly_rad.sin()
+        + 0.02 * (2. * anomaly_rad).sin()
+        + 0.0003 * (3. * anomaly_rad).sin();
+    let perihelion = 102.93005 + 0.3179526 * (day - J2000) / 36525.;
+    let ecliptic_longitude = (anomaly + center + 180. + perihelion % 360. + 360.) % 360.;
+    let transit =
+        day + 0.0053 * anomaly_rad.sin() - 0.0069 * (2. * ecliptic_longitude * DEGREE).sin();
+    let declination = ((ecliptic_longitude * DEGREE).sin() * 0.39779).asin() / DEGREE;
+    (transit, declination)
+}
+
+/// Unix timestamp of solar noon, when the sun is highest in the sky.
+pub fn solar_noon(lon: f64, date: NaiveDate) -> i64 {
+    let (transit, _) = transit_and_declination(lon, date);
+    julian_to_unix(transit)
+}
+
+/// Unix timestamps at which the sun passes through `elevation` degrees on
+/// the way up (morning) and on the way down (evening).
+///
+/// Near the poles the sun may never reach that elevation (or never drop to
+/// it). Rather than failing, we return the closest approach: solar midnight
+/// if it never gets that low, and solar noon if it never gets that high.
+pub fn sun_elevation_times(lat: f64, lon: f64, date: NaiveDate, elevation: f64) -> (i64, i64) {
+    let (transit, declination) = transit_and_declination(lon, date);
+    let lat_rad = lat * DEGREE;
+    let declination_rad = declination * DEGREE;
+    let cos_hour_angle = ((elevation * DEGREE).sin() - lat_rad.sin() * declination_rad.sin())
+        / (lat_rad.cos() * declination_rad.cos());
+    let hour_angle = cos_hour_angle.clamp(-1., 1.).acos() / DEGREE;
+    let frac = hour_angle / 360.;
+    (
+        julian_to_unix(transit - frac),
+        julian_to_unix(transit + frac),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Sun elevation (degrees) at which the upper limb touches the horizon,
+    /// allowing for refraction. This is what the `sunrise` crate calls sunrise.
+    const HORIZON: f64 = -0.833;
+
+    #[test]
+    fn test_horizon_matches_sunrise_crate() {
+        for (month, day) in [(1, 15), (3, 20), (6, 21), (9, 22), (12, 21)] {
+            let date = NaiveDate::from_ymd_opt(2024, month, day).unwrap();
+            let (rise, set) = sunrise::sunrise_sunset(49., -124., 2024, month, day);
+            let (our_rise, our_set) = sun_elevation_times(49., -124., date, HORIZON);
+            // The sunrise crate uses a rounded sin(-0.833), so allow a few seconds.
+            assert!((rise - our_rise).abs() < 5, "{} vs {}", rise, our_rise);
+            assert!((set - our_set).abs() < 5, "{} vs {}", set, our_set);
+        }
+    }
+
+    #[test]
+    fn test_twilight_ordering() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 20).unwrap();
+        let noon = solar_noon(-124., date);
+        let mut last_dawn = noon;
+        let mut last_dusk = noon;
+        for elevation in [
+            HORIZON,
+            CIVIL_TWILIGHT,
+            NAUTICAL_TWILIGHT,
+            ASTRONOMICAL_TWILIGHT,
+        ] {
+            let (dawn, dusk) = sun_elevation_times(49., -124., date, elevation);
+            assert!(dawn < last_dawn);
+            assert!(dusk > last_dusk);
+            last_dawn = dawn;
+            last_dusk = dusk;
+        }
+        // Noon should be roughly halfway between sunrise and sunset.
+        let (rise, set) = sun_elevation_times(49., -124., date, HORIZON);
+        assert!(((rise + set) / 2 - noon).abs() < 60);
+    }
+
+    #[test]
+    fn test_polar_days_clamp() {
+        // Svalbard in June: the sun never sets, let alone reaches -18 degrees.
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let noon = solar_noon(15.6, date);
+        let (dawn, dusk) = sun_elevation_times(78.2, 15.6, date, ASTRONOMICAL_TWILIGHT);
+        assert!((noon - dawn - 12 * 3600).abs() < 60);
+        assert!((dusk - noon - 12 * 3600).abs() < 60);
+        // And in December it never rises above the horizon.
+        let date = NaiveDate::from_ymd_opt(2024, 12, 21).unwrap();
+        let noon = solar_noon(15.6, date);
+        let (rise, set) = sun_elevation_times(78.2, 15.6, date, HORIZON);
+        assert_eq!(rise, noon);
+        assert_eq!(set, noon);
+    }
+}
@@ -0,0 +1,499 @@
+/// Tasmota bulbs and dimmers, driven over their `cm?cmnd=` HTTP API with the
+/// same schedules and min/max brightness config as WLEDs.
+use crate::backend::LightBackend;
+use crate::types::*;
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use reqwest::blocking::Client;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Mutex;
+use wled_json_api_library::structures::info::Info;
+use wled_json_api_library::structures::state::State;
+
+/// How many addresses are probed at once.
+const PROBE_WORKERS: usize = 16;
+/// The biggest range we'll probe (254 hosts), so a typo can't sweep a /8.
+const MIN_PROBE_PREFIX: u8 = 24;
+
+#[derive(Debug)]
+pub struct Tasmota {
+    pub name: String,
+    pub url: Url,
+    pub client: Client,
+    /// What we last sent and whether it's held, as for WLEDs.
+    pub memory: LedMemory,
+    pub health: DeviceHealth,
+    /// The brightness last sent. Tasmota only keeps it to the nearest
+    /// percent, so this is what we report back while it still matches.
+    sent_bri: Option<u8>,
+    /// The fade Speed last set, 0 for fading off. Tasmota keeps it as a
+    /// setting, so it only needs sending again when it changes.
+    sent_fade: Option<u8>,
+}
+
+impl Tasmota {
+    pub fn new(name: &str, url: Url, client: &Client) -> Tasmota {
+        Tasmota {
+            name: name.to_string(),
+            url,
+            client: client.clone(),
+            memory: LedMemory::default(),
+            health: DeviceHealth::default(),
+            sent_bri: None,
+            sent_fade: None,
+        }
+    }
+
+    /// Runs `commands` in one request, using Backlog for more than one.
+    pub fn send(&self, commands: &[String]) -> Result<serde_json::Value> {
+        let command = match commands {
+            [command] => command.clone(),
+            _ => format!("Backlog {}", commands.join("; ")),
+        };
+        let mut url = self.url.clone();
+        url.set_path("cm");
+        url.query_pairs_mut().append_pair("cmnd", &command);
+        debug!("Sending '{}' to Tasmota '{}'", command, self.name);
+        let response = self.client.get(url).send()?.error_for_status()?;
+        Ok(serde_json::from_str(&response.text()?)?)
+    }
+
+    /// Sends `command`, faded over `tt` tenths of a second.
+    fn send_faded(&mut self, command: String, tt: u8) -> Result<()> {
+        let fade = if tt > 0 { fade_speed(tt) } else { 0 };
+        let mut commands = fade_commands(self.sent_fade, fade);
+        commands.push(command);
+        let result = self.send(&commands);
+        // If it failed we can't tell what got through, so send it all next time.
+        self.sent_fade = result.is_ok().then_some(fade);
+        result.map(|_| ())
+    }
+}
+
+/// Converts a 0-255 brightness into Tasmota's 0-100 Dimmer.
+fn dimmer(bri: u8) -> u8 {
+    ((bri as u32 * 100 + 127) / 255) as u8
+}
+
+/// Converts kelvin into Tasmota's CT, in mireds (153-500).
+fn mireds(kelvin: u16) -> u16 {
+    ((1_000_000 + kelvin as u32 / 2) / kelvin.max(1) as u32).clamp(153, 500) as u16
+}
+
+/// Tasmota's Speed is in half seconds, 1-40.
+fn fade_speed(tt: u8) -> u8 {
+    ((tt as u32 + 2) / 5).clamp(1, 40) as u8
+}
+
+/// The commands that change the fade from `last` to `fade` (a Speed, or 0
+/// for no fade). Nothing, if it's already set.
+fn fade_commands(last: Option<u8>, fade: u8) -> Vec<String> {
+    match (last, fade) {
+        (Some(last), fade) if last == fade => vec![],
+        (_, 0) => vec!["Fade 0".to_string()],
+        (Some(last), fade) if last > 0 => vec![format!("Speed {}", fade)],
+        (_, fade) => vec!["Fade 1".to_string(), format!("Speed {}", fade)],
+    }
+}
+
+/// The command for `state`'s color. A Color takes the whole light, white
+/// channels included, so it wins over a CCT given alongside it.
+fn color_command(state: &LEDState) -> Option<String> {
+    match (&state.color, state.cct) {
+        (Some(color), _) => Some(format!(
+            "Color {}",
+            color
+                .iter()
+                .map(|channel| format!("{:02X}", channel))
+                .collect::<String>()
+        )),
+        (None, Some(cct)) => Some(format!("CT {}", mireds(cct))),
+        (None, None) => None,
+    }
+}
+
+/// Tasmota speaks power, dimmer and color; presets, playlists, nightlights,
+/// effects and segments are WLED-only, so they're accepted and skipped.
+impl LightBackend for Tasmota {
+    /// Tasmota has nothing like WLED's info, so there's nothing to fetch.
+    fn discover(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn info(&self) -> Option<&Info> {
+        None
+    }
+
+    /// Only power and brightness are read back. Tasmota reports its color
+    /// scaled by the dimmer, so it can't be compared with what we sent.
+    fn read_state(&mut self) -> Result<State> {
+        let reply = self.send(&["State".to_string()])?;
+        let power = reply
+            .get("POWER")
+            .or_else(|| reply.get("POWER1"))
+            .and_then(|power| power.as_str())
+            .ok_or_else(|| anyhow!("No power state from Tasmota '{}'", self.name))?;
+        let bri = reply
+            .get("Dimmer")
+            .and_then(|dimmer| dimmer.as_u64())
+            .map(|percent| match self.sent_bri {
+                Some(sent) if dimmer(sent) as u64 == percent => sent,
+                _ => ((percent.min(100) * 255 + 50) / 100) as u8,
+            });
+        Ok(State {
+            on: Some(power == "ON"),
+            bri,
+            ..Default::default()
+        })
+    }
+
+    fn set_power(&mut self, power: bool) -> Result<()> {
+        let command = if power { "Power ON" } else { "Power OFF" };
+        self.send(&[command.to_string()]).map(|_| ())
+    }
+
+    fn set_brightness(&mut self, bri: u8, tt: u8) -> Result<()> {
+        self.send_faded(format!("Dimmer {}", dimmer(bri)), tt)?;
+        self.sent_bri = Some(bri);
+        Ok(())
+    }
+
+    fn set_preset(&mut self, _preset: u16, _tt: u8) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_playlist(&mut self, _playlist: PlaylistState) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_nightlight(&mut self, _nightlight: &NightlightState) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_color(&mut self, state: &LEDState, tt: u8) -> Result<()> {
+        match color_command(state) {
+            Some(command) => self.send_faded(command, tt),
+            None => Ok(()),
+        }
+    }
+
+    fn set_segments(&mut self, _segments: &[(u8, u8, LEDState)], _tt: u8) -> Result<()> {
+        Ok(())
+    }
+
+    fn restore_state(&mut self, snapshot: &State) -> Result<()> {
+        if let Some(bri) = snapshot.bri {
+            self.set_brightness(bri, 0)?;
+        }
+        match snapshot.on {
+            Some(power) => self.set_power(power),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The hosts a `tasmota_probe` entry covers: a single host, or every host
+/// address in an IPv4 CIDR range such as "192.168.20.0/24".
+pub fn probe_hosts(spec: &str) -> Result<Vec<String>> {
+    let Some((network, prefix)) = spec.split_once('/') else {
+        return Ok(vec![spec.to_string()]);
+    };
+    let network: Ipv4Addr = network
+        .parse()
+        .map_err(|_| anyhow!("'{}' isn't an IPv4 range", spec))?;
+    let prefix: u8 = prefix
+        .parse()
+        .ok()
+        .filter(|prefix| (MIN_PROBE_PREFIX..=32).contains(prefix))
+        .ok_or_else(|| {
+            anyhow!(
+                "'{}' needs a prefix from /{} to /32",
+                spec,
+                MIN_PROBE_PREFIX
+            )
+        })?;
+    let mask = u32::MAX << (32 - prefix as u32);
+    let first = u32::from(network) & mask;
+    let last = first | !mask;
+    // Skip the network and broadcast addresses, where there are any.
+    let hosts = if prefix >= 31 {
+        first..=last
+    } else {
+        first + 1..=last - 1
+    };
+    Ok(hosts.map(|ip| Ipv4Addr::from(ip).to_string()).collect())
+}
+
+/// Asks whatever is at `url` for its Tasmota hostname.
+fn probe(url: &Url, client: &Client) -> Result<String> {
+    let device = Tasmota::new("probe", url.clone(), client);
+    let reply = device.send(&["Hostname".to_string()])?;
+    reply
+        .get("Hostname")
+        .and_then(|hostname| hostname.as_str())
+        .map(|hostname| hostname.to_string())
+        .ok_or_else(|| anyhow!("{} doesn't look like a Tasmota device", url))
+}
+
+fn tasmota_url(host: &str, port: u16) -> Result<Url> {
+    let host = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => host.to_string(),
+    };
+    Ok(Url::parse(&format!("http://{}:{}/", host, port))?)
+}
+
+/// Adds the Tasmota devices listed under `tasmota`.
+pub fn register_tasmota(
+    config: &Config,
+    found_tasmota: &mut HashMap<String, Tasmota>,
+    client: &Client,
+) {
+    for (name, device) in &config.tasmota {
+        let url = match tasmota_url(&device.host, device.port) {
+            Ok(url) => url,
+            Err(err) => {
+                warn!("Bad address for Tasmota '{}': {}", name, err);
+                continue;
+            }
+        };
+        if found_tasmota.get(name).is_some_and(|old| old.url == url) {
+            continue;
+        }
+        info!("Added Tasmota {} at: {}", name, url);
+        found_tasmota.insert(name.clone(), Tasmota::new(name, url, client));
+    }
+}
+
+/// Probes the `tasmota_probe` hosts from a thread of its own, so a slow
+/// range doesn't hold up the cycle. What it finds is picked up by `collect`.
+/// Dropping it stops the probing early.
+pub struct TasmotaProbe {
+    specs: Vec<String>,
+    found: Receiver<(String, Url)>,
+}
+
+impl TasmotaProbe {
+    pub fn start(config: &Config, client: &Client) -> TasmotaProbe {
+        let mut hosts = Vec::new();
+        for spec in &config.tasmota_probe {
+            match probe_hosts(spec) {
+                Ok(spec_hosts) => hosts.extend(spec_hosts),
+                Err(err) => warn!("Not probing for Tasmota devices: {}", err),
+            }
+        }
+        // Listed devices are already known, so don't ask them again.
+        let listed: Vec<Url> = config
+            .tasmota
+            .values()
+            .filter_map(|device| tasmota_url(&device.host, device.port).ok())
+            .collect();
+        let urls: Vec<Url> = hosts
+            .iter()
+            .filter_map(|host| tasmota_url(host, 80).ok())
+            .filter(|url| !listed.contains(url))
+            .collect();
+        let (sender, found) = channel();
+        if !urls.is_empty() {
+            let client = client.clone();
+            std::thread::Builder::new()
+                .name("tasmota-probe".to_string())
+                .spawn(move || {
+                    info!("Probing {} addresses for Tasmota devices.", urls.len());
+                    let jobs = Mutex::new(urls.iter().collect::<Vec<_>>());
+                    std::thread::scope(|scope| {
+                        for _ in 0..PROBE_WORKERS.min(urls.len()) {
+                            scope.spawn(|| loop {
+                                let next = jobs.lock().unwrap().pop();
+                                let Some(url) = next else {
+                                    break;
+                                };
+                                match probe(url, &client) {
+                                    Ok(hostname) => {
+                                        // Nobody's listening any more, so stop.
+                                        if sender.send((hostname, url.clone())).is_err() {
+                                            jobs.lock().unwrap().clear();
+                                        }
+                                    }
+                                    Err(err) => debug!("No Tasmota at {}: {}", url, err),
+                                }
+                            });
+                        }
+                    });
+                })
+                .expect("Failed to start the Tasmota probe thread");
+        }
+        TasmotaProbe {
+            specs: config.tasmota_probe.clone(),
+            found,
+        }
+    }
+
+    /// The `tasmota_probe` entries this is probing.
+    pub fn specs(&self) -> &[String] {
+        &self.specs
+    }
+
+    /// Adds the devices found since the last call. Probed devices are named
+    /// by their Tasmota hostname, and a listed device always wins.
+    pub fn collect(
+        &self,
+        config: &Config,
+        found_tasmota: &mut HashMap<String, Tasmota>,
+        client: &Client,
+    ) {
+        for (hostname, url) in self.found.try_iter() {
+            let listed = found_tasmota
+                .iter()
+                .any(|(name, device)| device.url == url && config.tasmota.contains_key(name));
+            if listed
+                || config.tasmota.contains_key(&hostname)
+                || found_tasmota
+                    .get(&hostname)
+                    .is_some_and(|old| old.url == url)
+            {
+                continue;
+            }
+            info!("Found Tasmota {} at: {}", hostname, url);
+            found_tasmota.insert(hostname.clone(), Tasmota::new(&hostname, url, client));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tasmota_commands() {
+        let state = LEDState {
+            bri: 0.5,
+            power: Some(true),
+            cct: Some(2700),
+            ..Default::default()
+        };
+        assert_eq!(color_command(&state), Some("CT 370".to_string()));
+        // A color takes over from the CCT, rather than both being sent.
+        let colored = LEDState {
+            color: Some(vec![255, 140, 0]),
+            ..state.clone()
+        };
+        assert_eq!(color_command(&colored), Some("Color FF8C00".to_string()));
+        assert_eq!(color_command(&LEDState::default()), None);
+
+        // The fade is only sent when it changes.
+        assert_eq!(fade_commands(None, 4), vec!["Fade 1", "Speed 4"]);
+        assert!(fade_commands(Some(4), 4).is_empty());
+        assert_eq!(fade_commands(Some(4), 6), vec!["Speed 6"]);
+        assert_eq!(fade_commands(Some(4), 0), vec!["Fade 0"]);
+        assert_eq!(fade_commands(Some(0), 4), vec!["Fade 1", "Speed 4"]);
+        assert_eq!(dimmer(128), 50);
+        assert_eq!(mireds(6500), 154);
+        assert_eq!(mireds(1900), 500);
+        assert_eq!(fade_speed(255), 40);
+    }
+
+    #[test]
+    fn test_tasmota_backend() {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        // Answers four requests like a bulb at 50%, returning the commands.
+        let server = std::thread::spawn(move || {
+            let mut commands = Vec::new();
+            for _ in 0..4 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let path = request.split(' ').nth(1).unwrap();
+                let url = Url::parse(&format!("http://bulb{}", path)).unwrap();
+                let (_, command) = url.query_pairs().next().unwrap();
+                let body = match command.as_ref() {
+                    "State" => r#"{"POWER":"ON","Dimmer":50}"#,
+                    _ => "{}",
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+                commands.push(command.to_string());
+            }
+            commands
+        });
+
+        let client = crate::util::http_client(&Config::default()).unwrap();
+        let mut device = Tasmota::new("bulb", url, &client);
+        device.set_brightness(128, 20).unwrap();
+        device.set_brightness(128, 20).unwrap();
+        // 50% is what we sent, so it reads back as the brightness we asked for.
+        let state = device.read_state().unwrap();
+        assert_eq!((state.on, state.bri), (Some(true), Some(128)));
+        let colored = LEDState {
+            cct: Some(2700),
+            color: Some(vec![255, 140, 0]),
+            ..Default::default()
+        };
+        device.set_color(&colored, 0).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "Backlog Fade 1; Speed 4; Dimmer 50",
+                "Dimmer 50",
+                "State",
+                "Backlog Fade 0; Color FF8C00"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_probe_hosts() {
+        assert_eq!(
+            probe_hosts("tasmota-bulb.lan").unwrap(),
+            vec!["tasmota-bulb.lan"]
+        );
+        let hosts = probe_hosts("192.168.20.77/24").unwrap();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], "192.168.20.1");
+        assert_eq!(hosts[253], "192.168.20.254");
+        assert_eq!(probe_hosts("10.0.0.5/32").unwrap(), vec!["10.0.0.5"]);
+        assert!(probe_hosts("10.0.0.0/8").is_err());
+        assert!(probe_hosts("10.0.0.0/23").is_err());
+        assert!(probe_hosts("fe80::/64").is_err());
+    }
+
+    #[test]
+    fn test_register_tasmota() {
+        let mut config = Config {
+            http_connect_timeout_ms: 200,
+            http_timeout_ms: 200,
+            ..Default::default()
+        };
+        config.tasmota.insert(
+            "porch-bulb".to_string(),
+            StaticDevice {
+                host: "127.0.0.1".to_string(),
+                port: 1,
+            },
+        );
+        // Nothing answers here, so nothing is found.
+        config.tasmota_probe.push("127.0.0.1".to_string());
+        let client = crate::util::http_client(&config).unwrap();
+        let mut found_tasmota = HashMap::new();
+        register_tasmota(&config, &mut found_tasmota, &client);
+        let probe = TasmotaProbe::start(&config, &client);
+        // The channel closes once the probe thread is done.
+        assert!(probe.found.recv().is_err());
+        probe.collect(&config, &mut found_tasmota, &client);
+        assert_eq!(found_tasmota.len(), 1);
+        assert_eq!(
+            found_tasmota["porch-bulb"].url.as_str(),
+            "http://127.0.0.1:1/"
+        );
+    }
+}
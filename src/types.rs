@@ -1,27 +1,59 @@
-use chrono::{Datelike, NaiveTime};
-use clap::Parser;
+use crate::live::LiveState;
+use crate::solar;
+use chrono::{
+    DateTime, Datelike, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Weekday,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use std::{collections::HashMap, path::PathBuf};
+use wled_json_api_library::structures::info::Info;
 use wled_json_api_library::structures::state::State;
 use wled_json_api_library::wled::Wled;
 
-/// Simple program to greet a person
+/// Schedules WLED brightness by time of day, and toggles LedFx on audio.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub(crate) struct Args {
-    /// Name of the person to greet
-    #[arg(short, long)]
+    /// Path to the config file (defaults to ~/.wled-doppler/config.ron)
+    #[arg(short, long, global = true)]
     pub config_path: Option<PathBuf>,
     // /// Number of times to greet
     // #[arg(short, long, default_value_t = 1)]
     // count: u8,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
-#[derive(Debug)]
-pub enum Device {
-    Wled(Wled),
-    Tasmota,
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Print what a schedule will do over a day, without touching any LEDs.
+    Simulate {
+        /// A schedule name, or an LED name from `leds` to also apply its
+        /// min/max brightness and curve.
+        target: String,
+        /// Day to simulate, as YYYY-MM-DD. Defaults to today.
+        #[arg(short, long)]
+        date: Option<NaiveDate>,
+        /// Time between rows, e.g. "15m" or "90s".
+        #[arg(short, long, default_value = "15m")]
+        step: humantime::Duration,
+        #[arg(short, long, value_enum, default_value_t = SimFormat::Table)]
+        format: SimFormat,
+        /// Write to this file instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Check the config for problems and exit non-zero if there are any.
+    Check,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SimFormat {
+    Table,
+    Csv,
+    Json,
 }
 
 #[derive(Debug)]
@@ -31,6 +63,170 @@ pub struct WLED {
     pub address: IpAddr,
     pub name: String,
     pub device: Wled,
+    pub memory: LedMemory,
+    pub health: DeviceHealth,
+    /// Set while `live_state` is on.
+    pub live: Option<LiveState>,
+}
+
+/// What we last did to a WLED, so each cycle only sends what changed.
+#[derive(Debug, Default)]
+pub struct LedMemory {
+    pub last_command: Option<LEDState>,
+    pub last_segments: Option<Vec<(u8, u8, LEDState)>>,
+    /// Set when the WLED was changed by hand; we start scheduling it again
+    /// at this timestamp.
+    pub held_until: Option<i64>,
+    /// The WLED's state from just before LedFx took it over.
+    pub ledfx_snapshot: Option<State>,
+}
+
+impl LedMemory {
+    /// Forgets what we last sent, so the next update sends everything.
+    pub fn forget_sent(&mut self) {
+        self.last_command = None;
+        self.last_segments = None;
+    }
+}
+
+/// Which kind of address to use for a WLED that has both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum AddressFamily {
+    #[default]
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn matches(&self, address: &IpAddr) -> bool {
+        match self {
+            AddressFamily::V4 => address.is_ipv4(),
+            AddressFamily::V6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// A device configured by address: a WLED on a network mDNS can't reach,
+/// or a Tasmota device.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StaticDevice {
+    /// IP address or hostname.
+    pub host: String,
+    #[serde(default = "default_wled_port")]
+    pub port: u16,
+}
+
+fn default_wled_port() -> u16 {
+    80
+}
+
+/// An MQTT broker to publish state to and take commands from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Every topic we publish or subscribe to starts with this.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "ledfx-trigger".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "ledfx-trigger".to_string()
+}
+
+/// Consecutive failures before a degraded device is reported offline.
+pub const OFFLINE_AFTER_FAILURES: u32 = 3;
+/// The longest we wait between retries of a failing device.
+pub const MAX_BACKOFF_SECONDS: f64 = 300.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub enum HealthState {
+    #[default]
+    Online,
+    /// Failing, but not for long.
+    Degraded,
+    Offline,
+    /// Withdrawn from mDNS, or its records expired.
+    Gone,
+}
+
+/// How a device has been answering us.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DeviceHealth {
+    pub state: HealthState,
+    /// Timestamp of the last cycle where everything we sent got through.
+    pub last_success: Option<i64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// A failing device is left alone until this timestamp.
+    pub retry_at: Option<i64>,
+}
+
+impl DeviceHealth {
+    /// Whether the device is due an update at `now`.
+    pub fn should_retry(&self, now: i64) -> bool {
+        self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+
+    /// Records a good update. Returns the old state if this changed it.
+    pub fn record_success(&mut self, now: i64) -> Option<HealthState> {
+        let old = self.state;
+        self.state = HealthState::Online;
+        self.last_success = Some(now);
+        self.consecutive_failures = 0;
+        self.last_error = None;
+        self.retry_at = None;
+        (old != self.state).then_some(old)
+    }
+
+    /// Records a failed update and schedules the next try, doubling the wait
+    /// from `cycle_seconds` up to MAX_BACKOFF_SECONDS. Returns the old state
+    /// if this changed it.
+    pub fn record_failure(
+        &mut self,
+        now: i64,
+        cycle_seconds: f64,
+        error: String,
+    ) -> Option<HealthState> {
+        let old = self.state;
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.state = if self.consecutive_failures >= OFFLINE_AFTER_FAILURES {
+            HealthState::Offline
+        } else {
+            HealthState::Degraded
+        };
+        self.last_error = Some(error);
+        self.retry_at = Some(now + self.backoff_seconds(cycle_seconds) as i64);
+        (old != self.state).then_some(old)
+    }
+
+    /// Records that the device has left the network. Returns the old state
+    /// if this changed it.
+    pub fn record_gone(&mut self) -> Option<HealthState> {
+        let old = self.state;
+        self.state = HealthState::Gone;
+        self.retry_at = None;
+        (old != self.state).then_some(old)
+    }
+
+    /// How long after the latest failure the next try happens.
+    pub fn backoff_seconds(&self, cycle_seconds: f64) -> f64 {
+        let doublings = self.consecutive_failures.saturating_sub(1).min(16);
+        (cycle_seconds * 2f64.powi(doublings as i32)).min(MAX_BACKOFF_SECONDS)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -49,24 +245,65 @@ pub enum ScheduleTime {
     Sunset,
     SunsetOffset(i16),
     Time(chrono::NaiveTime),
+    /// Sun 6 degrees below the horizon, morning/evening.
+    CivilDawn,
+    CivilDusk,
+    /// Sun 12 degrees below the horizon.
+    NauticalDawn,
+    NauticalDusk,
+    /// Sun 18 degrees below the horizon; full night after dusk.
+    AstronomicalDawn,
+    AstronomicalDusk,
+    /// The sun at its highest point of the day.
+    SolarNoon,
+    /// The sun passing through the given elevation in degrees (negative is
+    /// below the horizon), on the way up if `rising`, otherwise going down.
+    SunElevation {
+        degrees: f64,
+        rising: bool,
+    },
+    /// Any other time shifted by a number of seconds, e.g. `Offset(CivilDusk, -600)`.
+    Offset(Box<ScheduleTime>, i32),
+}
+
+/// Converts a wall-clock time to a unix timestamp in the given zone. Times
+/// skipped by a DST jump are pushed forward by the size of the jump (so 02:30
+/// on a spring-forward night becomes 03:30), and times that happen twice when
+/// the clocks go back resolve to the first occurrence.
+pub fn local_to_timestamp<Tz: TimeZone>(tz: &Tz, local: NaiveDateTime) -> i64 {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(datetime) => datetime.timestamp(),
+        LocalResult::Ambiguous(earliest, _) => earliest.timestamp(),
+        LocalResult::None => {
+            // Read the wall clock using the offset from just before the gap.
+            let offset = (1..=24)
+                .find_map(|hours| {
+                    tz.from_local_datetime(&(local - chrono::Duration::hours(hours)))
+                        .earliest()
+                })
+                .map(|before| before.offset().fix().local_minus_utc())
+                .unwrap_or(0);
+            local.and_utc().timestamp() - offset as i64
+        }
+    }
 }
 
 impl ScheduleTime {
-    pub fn to_timestamp(&self, lat: f64, lon: f64) -> u64 {
-        let today_date = chrono::Local::now();
-        // let tz = today_date.timezone();
-        // println!("TZ IS {:?}", tz);
-        // let offset = tz
-        //     .offset_from_local_datetime(&today_date.naive_local())
-        //     .unwrap();
-        // println!("Offset is {:?} ({}s)", offset, offset.utc_minus_local());
-        // let offset_seconds = offset.utc_minus_local() as u64;
-        let today_date = today_date.date();
+    /// Resolves this time against the calendar day `now` falls on, in `now`'s zone.
+    pub fn to_timestamp<Tz: TimeZone>(&self, now: &DateTime<Tz>, lat: f64, lon: f64) -> u64 {
+        self.to_timestamp_on(now.date_naive(), &now.timezone(), lat, lon)
+    }
+
+    /// Resolves this time against a specific calendar day in the given zone.
+    pub fn to_timestamp_on<Tz: TimeZone>(
+        &self,
+        today_date: NaiveDate,
+        tz: &Tz,
+        lat: f64,
+        lon: f64,
+    ) -> u64 {
         match self {
-            Self::Time(time) => {
-                let today_date = today_date.and_time(*time).expect("Invalid input time");
-                today_date.timestamp() as u64
-            }
+            Self::Time(time) => local_to_timestamp(tz, today_date.and_time(*time)) as u64,
             Self::Sunrise => {
                 let (sunrise_time, _) = sunrise::sunrise_sunset(
                     lat,
@@ -107,6 +344,38 @@ impl ScheduleTime {
                 );
                 (sunset_time + *seconds as i64) as u64 // + offset_seconds
             }
+            Self::CivilDawn => {
+                solar::sun_elevation_times(lat, lon, today_date, solar::CIVIL_TWILIGHT).0 as u64
+            }
+            Self::CivilDusk => {
+                solar::sun_elevation_times(lat, lon, today_date, solar::CIVIL_TWILIGHT).1 as u64
+            }
+            Self::NauticalDawn => {
+                solar::sun_elevation_times(lat, lon, today_date, solar::NAUTICAL_TWILIGHT).0 as u64
+            }
+            Self::NauticalDusk => {
+                solar::sun_elevation_times(lat, lon, today_date, solar::NAUTICAL_TWILIGHT).1 as u64
+            }
+            Self::AstronomicalDawn => {
+                solar::sun_elevation_times(lat, lon, today_date, solar::ASTRONOMICAL_TWILIGHT).0
+                    as u64
+            }
+            Self::AstronomicalDusk => {
+                solar::sun_elevation_times(lat, lon, today_date, solar::ASTRONOMICAL_TWILIGHT).1
+                    as u64
+            }
+            Self::SolarNoon => solar::solar_noon(lon, today_date) as u64,
+            Self::SunElevation { degrees, rising } => {
+                let (dawn, dusk) = solar::sun_elevation_times(lat, lon, today_date, *degrees);
+                if *rising {
+                    dawn as u64
+                } else {
+                    dusk as u64
+                }
+            }
+            Self::Offset(time, seconds) => {
+                (time.to_timestamp_on(today_date, tz, lat, lon) as i64 + *seconds as i64) as u64
+            }
         }
     }
 }
@@ -116,11 +385,223 @@ pub enum WLEDChange {
     Brightness(f32),
     Preset(u16),
     Power(bool),
+    /// White color temperature in Kelvin (WLED takes 1900-10091).
+    Cct(u16),
+    /// Primary color as [r, g, b] or [r, g, b, w].
+    Color(Vec<u8>),
+    /// Effect by ID, or by name as listed on the device (e.g. "Rainbow").
+    Effect(NameOrId),
+    /// Palette by ID, or by name as listed on the device.
+    Palette(NameOrId),
+    /// Effect speed, 0-255. Blends between keyframes like brightness.
+    Speed(u8),
+    /// Effect intensity, 0-255. Blends between keyframes like brightness.
+    Intensity(u8),
+    /// Start the playlist saved in this preset slot.
+    Playlist(u16),
+    StopPlaylist,
+    /// Hand over to the WLED's own nightlight for `duration` minutes. The
+    /// schedule leaves the light alone until that's done.
+    Nightlight {
+        duration: u8,
+        target_bri: u8,
+        #[serde(default = "default_nightlight_mode")]
+        mode: NightlightMode,
+    },
     None,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum NightlightMode {
+    Instant,
+    #[default]
+    Fade,
+    ColorFade,
+    Sunrise,
+}
+
+impl NightlightMode {
+    /// WLED's number for this mode.
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            NightlightMode::Instant => 0,
+            NightlightMode::Fade => 1,
+            NightlightMode::ColorFade => 2,
+            NightlightMode::Sunrise => 3,
+        }
+    }
+}
+
+fn default_nightlight_mode() -> NightlightMode {
+    NightlightMode::Fade
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum PlaylistState {
+    Running(u16),
+    Stopped,
+}
+
+/// A nightlight that a schedule has started, and is still running.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct NightlightState {
+    /// Unix timestamp of the keyframe that started it.
+    pub started: u64,
+    pub duration: u8,
+    pub target_bri: u8,
+    pub mode: NightlightMode,
+}
+
+/// Effects and palettes can be given by their number, or by name. Names are
+/// looked up on each device, since the lists differ between WLED builds.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum NameOrId {
+    Id(u16),
+    Name(String),
+}
+
+impl std::fmt::Display for NameOrId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameOrId::Id(id) => write!(f, "{}", id),
+            NameOrId::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// What a schedule says an LED should be doing at a given moment.
+/// Everything but brightness is optional, since schedules don't have to
+/// touch presets, power or color at all.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LEDState {
+    /// Scheduled brightness, 0.0-1.0 (before min_bri/max_bri are applied).
+    pub bri: f32,
+    pub preset: Option<u16>,
+    pub power: Option<bool>,
+    pub cct: Option<u16>,
+    pub color: Option<Vec<u8>>,
+    pub effect: Option<NameOrId>,
+    pub palette: Option<NameOrId>,
+    pub speed: Option<u8>,
+    pub intensity: Option<u8>,
+    pub playlist: Option<PlaylistState>,
+    pub nightlight: Option<NightlightState>,
+}
+
+impl LEDState {
+    /// True if the schedule sets any of the things that go to a segment
+    /// (color, effect and so on) rather than to the whole device.
+    pub fn has_segment_changes(&self) -> bool {
+        self.cct.is_some()
+            || self.color.is_some()
+            || self.effect.is_some()
+            || self.palette.is_some()
+            || self.speed.is_some()
+            || self.intensity.is_some()
+    }
+
+    pub fn same_segment_changes(&self, other: &LEDState) -> bool {
+        self.cct == other.cct
+            && self.color == other.color
+            && self.effect == other.effect
+            && self.palette == other.palette
+            && self.speed == other.speed
+            && self.intensity == other.intensity
+    }
+}
+
+/// How brightness moves from one keyframe to the next. Set on the keyframe
+/// that starts the segment.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum Interpolation {
+    /// Hold this keyframe's value, then snap to the next one when it arrives.
+    Step,
+    #[default]
+    Linear,
+    /// Starts slow, finishes fast.
+    EaseIn,
+    /// Starts fast, finishes slow.
+    EaseOut,
+    /// Slow at both ends (smoothstep).
+    EaseInOut,
+    /// Constant ratio of change per second, which looks even to the eye
+    /// when dimming over a long period.
+    Exponential,
+}
+
+impl Interpolation {
+    /// Blends `from` toward `to`, where `t` is how far (0.0-1.0) we are
+    /// through the segment.
+    pub fn interpolate(&self, from: f32, to: f32, t: f32) -> f32 {
+        let t = t.clamp(0., 1.);
+        let shaped = match self {
+            Self::Step if t >= 1. => 1.,
+            Self::Step => 0.,
+            Self::Linear => t,
+            Self::EaseIn => t * t,
+            Self::EaseOut => 1. - (1. - t) * (1. - t),
+            Self::EaseInOut => t * t * (3. - 2. * t),
+            Self::Exponential => {
+                if t >= 1. {
+                    return to;
+                }
+                // Geometric blending can't start or end at zero, so floor
+                // both ends at one WLED brightness step.
+                let floor = 1. / 255.;
+                let (from_exp, to_exp) = (from.max(floor), to.max(floor));
+                return from_exp * (to_exp / from_exp).powf(t);
+            }
+        };
+        from + shaped * (to - from)
+    }
+}
+
+/// Which days of the week a schedule item applies on.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum ActiveDays {
+    #[default]
+    Daily,
+    /// Monday through Friday.
+    Weekdays,
+    /// Saturday and Sunday.
+    Weekends,
+    /// An explicit list, e.g. `Only(["Mon", "Wed", "Fri"])`.
+    Only(Vec<Weekday>),
+}
+
+impl ActiveDays {
+    pub fn contains(&self, day: Weekday) -> bool {
+        match self {
+            Self::Daily => true,
+            Self::Weekdays => !matches!(day, Weekday::Sat | Weekday::Sun),
+            Self::Weekends => matches!(day, Weekday::Sat | Weekday::Sun),
+            Self::Only(days) => days.contains(&day),
+        }
+    }
+}
+
+/// An inclusive (month, day) range that repeats every year. If `from` is
+/// later in the year than `until` the range wraps over New Year.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DateRange {
+    pub from: (u32, u32),
+    pub until: (u32, u32),
+}
+
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        let month_day = (date.month(), date.day());
+        if self.from <= self.until {
+            self.from <= month_day && month_day <= self.until
+        } else {
+            month_day >= self.from || month_day <= self.until
+        }
+    }
+}
+
 // These are used as a list of times with intensities 0-255.
-// We interpolate linearly, and treat each day as a loop, so
+// We interpolate (linearly by default), and treat each day as a loop, so
 // we interpolate between the last time in the previous day,
 // and the first time today.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -129,6 +610,26 @@ pub struct WLEDScheduleItem {
     pub time: ScheduleTime,
     // pub preset: Option<u32>,
     pub change: WLEDChange,
+    /// Shape of the brightness segment that starts at this keyframe.
+    #[serde(default = "default_interpolation")]
+    pub interpolation: Interpolation,
+    /// Days of the week this item is used on.
+    #[serde(default = "default_days")]
+    pub days: ActiveDays,
+    /// Optional part of the year this item is used in.
+    pub dates: Option<DateRange>,
+}
+
+impl WLEDScheduleItem {
+    /// Whether this item takes part in the schedule on the given day.
+    pub fn is_active_on(&self, date: NaiveDate) -> bool {
+        self.days.contains(date.weekday())
+            && self
+                .dates
+                .as_ref()
+                .map(|dates| dates.contains(date))
+                .unwrap_or(true)
+    }
 }
 
 impl Default for WLEDScheduleItem {
@@ -139,6 +640,9 @@ impl Default for WLEDScheduleItem {
                     .expect("Total chrono library failure."),
             ),
             change: WLEDChange::None,
+            interpolation: default_interpolation(),
+            days: default_days(),
+            dates: None,
         }
     }
 }
@@ -157,6 +661,30 @@ fn default_cycle() -> f64 {
     10.0
 }
 
+fn default_http_connect_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_http_timeout_ms() -> u64 {
+    3000
+}
+
+fn default_max_parallel_updates() -> usize {
+    8
+}
+
+fn default_interpolation() -> Interpolation {
+    Interpolation::Linear
+}
+
+fn default_days() -> ActiveDays {
+    ActiveDays::Daily
+}
+
+fn default_curve() -> BrightnessCurve {
+    BrightnessCurve::Linear
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum LEDScheduleSpec {
     Default,
@@ -164,11 +692,227 @@ pub enum LEDScheduleSpec {
     None,
 }
 
+/// Response curve used to turn a scheduled brightness (0.0-1.0) into a
+/// position between an LED's min_bri and max_bri. LEDs look far brighter
+/// at low duty cycles than a linear ramp suggests, so the perceptual curves
+/// spend more of the range down at the dim end.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum BrightnessCurve {
+    #[default]
+    Linear,
+    /// Power-law curve; 2.2 is a reasonable starting point.
+    Gamma(f32),
+    /// CIE 1931 lightness (L*) curve.
+    Cie,
+}
+
+impl BrightnessCurve {
+    /// Maps a 0.0-1.0 fraction through the curve, returning 0.0-1.0.
+    pub fn apply(&self, pc: f32) -> f32 {
+        let pc = pc.clamp(0., 1.);
+        match self {
+            Self::Linear => pc,
+            Self::Gamma(gamma) => pc.powf(gamma.max(0.01)),
+            Self::Cie => {
+                let lightness = pc * 100.;
+                if lightness <= 8. {
+                    lightness / 903.3
+                } else {
+                    ((lightness + 16.) / 116.).powi(3)
+                }
+            }
+        }
+    }
+}
+
+/// A set of WLEDs that share one config, picked out by pattern rather than
+/// listed one by one in `leds`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LEDGroup {
+    pub members: GroupMatch,
+    /// When a WLED is in more than one group, the highest priority wins,
+    /// then the group whose name sorts first.
+    #[serde(default)]
+    pub priority: i32,
+    pub config: LEDBrightnessConfig,
+}
+
+/// How a group chooses its WLEDs. Names are the full mDNS names (or the
+/// names given under `devices`), matched case-insensitively.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum GroupMatch {
+    /// Shell-style pattern, e.g. "wled-kitchen-*". `*` matches any run of
+    /// characters and `?` any one.
+    Glob(String),
+    Regex(GroupRegex),
+    Names(Vec<String>),
+    /// Fields from the WLED's own info. Every field given has to match.
+    Info(InfoMatch),
+}
+
+/// A group's regex, compiled (case-insensitively) as the config is read, so
+/// a bad pattern fails the load rather than quietly matching nothing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct GroupRegex(regex::Regex);
+
+impl TryFrom<String> for GroupRegex {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        regex::RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .map(GroupRegex)
+    }
+}
+
+impl From<GroupRegex> for String {
+    fn from(regex: GroupRegex) -> String {
+        regex.0.as_str().to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InfoMatch {
+    #[serde(default)]
+    pub product: Option<String>,
+    #[serde(default)]
+    pub brand: Option<String>,
+    #[serde(default)]
+    pub arch: Option<String>,
+    #[serde(default)]
+    pub min_leds: Option<u16>,
+    #[serde(default)]
+    pub max_leds: Option<u16>,
+}
+
+impl GroupMatch {
+    /// Whether the WLED `name` (with `info`, if we have it) is in the group.
+    pub fn matches(&self, name: &str, info: Option<&Info>) -> bool {
+        match self {
+            GroupMatch::Glob(pattern) => glob_match(
+                &pattern.to_lowercase().chars().collect::<Vec<_>>(),
+                &name.to_lowercase().chars().collect::<Vec<_>>(),
+            ),
+            GroupMatch::Regex(regex) => regex.0.is_match(name),
+            GroupMatch::Names(names) => {
+                names.iter().any(|member| member.eq_ignore_ascii_case(name))
+            }
+            GroupMatch::Info(want) => info.is_some_and(|info| want.matches(info)),
+        }
+    }
+}
+
+impl InfoMatch {
+    fn matches(&self, info: &Info) -> bool {
+        let same = |want: &Option<String>, have: &Option<String>| match want {
+            Some(want) => have
+                .as_ref()
+                .is_some_and(|have| have.eq_ignore_ascii_case(want)),
+            None => true,
+        };
+        let count = info.leds.as_ref().and_then(|leds| leds.count);
+        same(&self.product, &info.product)
+            && same(&self.brand, &info.brand)
+            && same(&self.arch, &info.arch)
+            && self
+                .min_leds
+                .is_none_or(|min| count.is_some_and(|count| count >= min))
+            && self
+                .max_leds
+                .is_none_or(|max| count.is_some_and(|count| count <= max))
+    }
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((want, rest)) => text
+            .split_first()
+            .is_some_and(|(have, text)| (*want == '?' || want == have) && glob_match(rest, text)),
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LEDBrightnessConfig {
     pub schedule: LEDScheduleSpec,
     pub min_bri: u8,
     pub max_bri: u8,
+    #[serde(default = "default_curve")]
+    pub curve: BrightnessCurve,
+    /// How long the WLED itself should fade to each new setting. When unset
+    /// this is one cycle, so the fade finishes just as the next update lands.
+    pub transition_ms: Option<u32>,
+    /// Optional per-segment schedules, keyed by WLED segment ID. These are
+    /// applied on top of (and scaled by) the device-wide brightness above.
+    #[serde(default = "default_segments")]
+    pub segments: HashMap<u8, SegmentConfig>,
+    /// What to do when someone changes the light by hand.
+    #[serde(default = "default_manual_override")]
+    pub manual_override: OverridePolicy,
+    /// What to do with the WLED when LedFx is done with it.
+    #[serde(default = "default_ledfx_handoff")]
+    pub ledfx_handoff: LedFxHandoff,
+}
+
+/// How long to leave a WLED alone after noticing that it was changed by
+/// hand (in the WLED app, a button, etc.) since we last set it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum OverridePolicy {
+    /// Don't check; the schedule always wins.
+    Ignore,
+    /// Hold off for this many seconds.
+    Hold(u64),
+    /// Hold off until the schedule's next keyframe.
+    #[default]
+    UntilNextKeyframe,
+}
+
+fn default_manual_override() -> OverridePolicy {
+    OverridePolicy::UntilNextKeyframe
+}
+
+/// How a WLED is handed back from LedFx once it pauses. Its state is
+/// snapshotted when LedFx starts either way.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LedFxHandoff {
+    /// Send everything the schedule says, as if we'd never seen the WLED.
+    #[default]
+    Reschedule,
+    /// Put back the segments, colors and effects it had before LedFx.
+    Restore,
+}
+
+fn default_ledfx_handoff() -> LedFxHandoff {
+    LedFxHandoff::Reschedule
+}
+
+/// Schedule and brightness range for one segment of a multi-segment WLED.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SegmentConfig {
+    pub schedule: LEDScheduleSpec,
+    pub min_bri: u8,
+    pub max_bri: u8,
+    #[serde(default = "default_curve")]
+    pub curve: BrightnessCurve,
+}
+
+fn default_segments() -> HashMap<u8, SegmentConfig> {
+    HashMap::new()
+}
+
+impl LEDBrightnessConfig {
+    /// The transition to send to WLED, in its units of 100ms. WLED only
+    /// takes a u8, so anything over 25.5 seconds is capped.
+    pub fn transition_tt(&self, cycle_seconds: f64) -> u8 {
+        let ms = match self.transition_ms {
+            Some(ms) => ms as f64,
+            None => cycle_seconds * 1000.,
+        };
+        (ms / 100.).round().clamp(0., u8::MAX as f64) as u8
+    }
 }
 
 impl Default for LEDBrightnessConfig {
@@ -177,6 +921,11 @@ impl Default for LEDBrightnessConfig {
             schedule: LEDScheduleSpec::None,
             min_bri: 20,
             max_bri: 128,
+            curve: default_curve(),
+            transition_ms: None,
+            segments: default_segments(),
+            manual_override: default_manual_override(),
+            ledfx_handoff: default_ledfx_handoff(),
         }
     }
 }
@@ -205,7 +954,13 @@ pub struct LedFxSchedule {
 pub struct Config {
     pub lat: f32,
     pub lon: f32,
+    /// IANA zone name (e.g. "America/Vancouver") used to resolve schedule
+    /// times. Falls back to $TZ and then the system zone when unset.
+    pub timezone: Option<String>,
     pub leds: HashMap<String, LEDBrightnessConfig>,
+    /// Configs for WLEDs chosen by pattern. An entry in `leds` always wins.
+    #[serde(default)]
+    pub groups: HashMap<String, LEDGroup>,
     // pub brightnesses: HashMap<String, (u8, u8)>,
     // pub transition_duration: i64, // How long it takes to go full dim from full bright
     pub loglevel: usize, //0: off, 1: error, 2: warn, 3: info, 4: debug, 5: pedantic
@@ -217,10 +972,40 @@ pub struct Config {
     pub ledfx_schedule: Option<LedFxSchedule>,
     #[serde(default = "default_cycle")]
     pub cycle_seconds: f64,
+    /// How long to wait for a WLED to accept a connection.
+    #[serde(default = "default_http_connect_timeout_ms")]
+    pub http_connect_timeout_ms: u64,
+    /// How long a whole request to a WLED may take, connecting included.
+    #[serde(default = "default_http_timeout_ms")]
+    pub http_timeout_ms: u64,
+    /// How many WLEDs are updated at once.
+    #[serde(default = "default_max_parallel_updates")]
+    pub max_parallel_updates: usize,
     #[serde(default = "default_schedule")]
     pub schedule: HashMap<String, WLEDSchedule>,
     #[serde(default = "default_cfg_change")]
     pub restart_on_cfg_change: CfgChangeAction,
+    /// WLEDs to use without mDNS, keyed by the name used in `leds`. These
+    /// win over any mDNS announcement with the same name or address.
+    #[serde(default)]
+    pub devices: HashMap<String, StaticDevice>,
+    /// Tasmota lights, keyed by the name used in `leds` (or matched by a
+    /// group). They follow schedules like WLEDs do.
+    #[serde(default)]
+    pub tasmota: HashMap<String, StaticDevice>,
+    /// Hosts or IPv4 ranges (e.g. "192.168.20.0/24") to look for Tasmota
+    /// devices on. Those found are named by their Tasmota hostname.
+    #[serde(default)]
+    pub tasmota_probe: Vec<String>,
+    /// Address family to use when a WLED announces both.
+    #[serde(default)]
+    pub prefer_address: AddressFamily,
+    /// Follow each WLED's state over its WebSocket, so changes made by hand
+    /// are seen without asking for them.
+    #[serde(default)]
+    pub live_state: bool,
+    /// Publish state to (and take commands from) an MQTT broker.
+    pub mqtt: Option<MqttConfig>,
     #[serde(default = "default_tray_icon")]
     pub tray_icon: bool,
     pub bind_address: Option<String>,
@@ -230,22 +1015,89 @@ pub struct Config {
 }
 
 impl Config {
+    /// The config for the WLED `name`: its own entry in `leds` if it has one,
+    /// else the best group it's in. `info` is needed for `Info` groups.
+    pub fn led_config(&self, name: &str, info: Option<&Info>) -> Option<&LEDBrightnessConfig> {
+        self.leds.get(name).or_else(|| {
+            self.groups
+                .iter()
+                .filter(|(_, group)| group.members.matches(name, info))
+                .min_by(|(a_name, a), (b_name, b)| {
+                    b.priority.cmp(&a.priority).then_with(|| a_name.cmp(b_name))
+                })
+                .map(|(_, group)| &group.config)
+        })
+    }
+
+    /// Whether any group needs a WLED's info to tell if it's a member.
+    pub fn has_info_groups(&self) -> bool {
+        self.groups
+            .values()
+            .any(|group| matches!(group.members, GroupMatch::Info(_)))
+    }
+
+    /// Looks up the schedule an LED's spec refers to. Returns None for
+    /// `LEDScheduleSpec::None` or if the named schedule doesn't exist.
+    pub fn led_schedule(&self, spec: &LEDScheduleSpec) -> Option<&WLEDSchedule> {
+        match spec {
+            LEDScheduleSpec::Default => self.schedule.get("default"),
+            LEDScheduleSpec::ByName(name) => self.schedule.get(name),
+            LEDScheduleSpec::None => None,
+        }
+    }
+
+    /// The zone schedules are resolved in: the configured `timezone`, else
+    /// `$TZ`, else the system zone, else UTC. Falling back to UTC is warned
+    /// about (once), as every schedule will be hours out.
+    pub fn zone(&self) -> chrono_tz::Tz {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        let name = self
+            .timezone
+            .clone()
+            .or_else(|| std::env::var("TZ").ok())
+            .or_else(|| iana_time_zone::get_timezone().ok());
+        let zone = match &name {
+            Some(name) => name
+                .trim_start_matches(':')
+                .parse()
+                .map_err(|err| format!("Unknown timezone '{}' ({})", name, err)),
+            None => Err("Couldn't find the system timezone".to_string()),
+        };
+        zone.unwrap_or_else(|err| {
+            WARNED.call_once(|| warn!("{}; scheduling in UTC. Set `timezone` in the config.", err));
+            chrono_tz::UTC
+        })
+    }
+
+    /// The current time in the schedule's zone.
+    pub fn now(&self) -> DateTime<chrono_tz::Tz> {
+        chrono::Utc::now().with_timezone(&self.zone())
+    }
+
     pub fn next_ledfx_transition(&self) -> Option<(ScheduleTime, Option<bool>)> {
         match self.ledfx_schedule.clone() {
             Some(ledfx_schedule) => {
-                let from_ts = ledfx_schedule
-                    .from
-                    .to_timestamp(self.lat as f64, self.lon as f64);
-                let until_ts = ledfx_schedule
-                    .until
-                    .to_timestamp(self.lat as f64, self.lon as f64);
-                if chrono::Local::now().timestamp() < from_ts as i64 {
+                let now = self.now();
+                let from_ts =
+                    ledfx_schedule
+                        .from
+                        .to_timestamp(&now, self.lat as f64, self.lon as f64);
+                let until_ts =
+                    ledfx_schedule
+                        .until
+                        .to_timestamp(&now, self.lat as f64, self.lon as f64);
+                if now.timestamp() < from_ts as i64 {
                     Some((ledfx_schedule.from.clone(), Some(true)))
-                } else if chrono::Local::now().timestamp() < until_ts as i64 {
+                } else if now.timestamp() < until_ts as i64 {
                     Some((ledfx_schedule.until.clone(), Some(false)))
                 } else {
                     //let tomorrow_midnight = (now + Duration::days(1)).date().and_hms(0, 0, 0);
-                    Some((ScheduleTime::Time(NaiveTime::from_hms(23, 59, 59)), None))
+                    Some((
+                        ScheduleTime::Time(
+                            NaiveTime::from_hms_opt(23, 59, 59).expect("Valid time"),
+                        ),
+                        None,
+                    ))
                     // No more transitions tonight
                 }
             }
@@ -269,10 +1121,12 @@ pub(crate) fn default_schedule() -> HashMap<String, WLEDSchedule> {
             WLEDScheduleItem {
                 time: ScheduleTime::Sunrise,
                 change: WLEDChange::Brightness(1.0),
+                ..Default::default()
             },
             WLEDScheduleItem {
                 time: ScheduleTime::Sunset,
                 change: WLEDChange::Brightness(0.2),
+                ..Default::default()
             },
         ],
     )])
@@ -287,8 +1141,10 @@ impl Default for Config {
         Self {
             lat: Default::default(),
             lon: Default::default(),
+            timezone: None,
             // exclusions: Default::default(),
             leds: HashMap::new(),
+            groups: HashMap::new(),
             // brightnesses: Default::default(),
             // transition_duration: Default::default(),
             loglevel: Default::default(),
@@ -298,8 +1154,17 @@ impl Default for Config {
             ledfx_idle_cycles: Default::default(),
             ledfx_schedule: Default::default(),
             cycle_seconds: Default::default(),
+            http_connect_timeout_ms: default_http_connect_timeout_ms(),
+            http_timeout_ms: default_http_timeout_ms(),
+            max_parallel_updates: default_max_parallel_updates(),
             schedule: default_schedule(),
             restart_on_cfg_change: default_cfg_change(),
+            devices: HashMap::new(),
+            tasmota: HashMap::new(),
+            tasmota_probe: Vec::new(),
+            prefer_address: AddressFamily::V4,
+            live_state: false,
+            mqtt: None,
             tray_icon: false,
             bind_address: None,
             vis_schedule: None,
@@ -310,97 +1175,282 @@ impl Default for Config {
 
 #[cfg(test)]
 mod test {
-    use crate::types::ScheduleTime;
-    use chrono::Local;
+    use crate::types::{
+        local_to_timestamp, Config, DeviceHealth, GroupMatch, HealthState, InfoMatch,
+        LEDBrightnessConfig, LEDGroup, ScheduleTime, MAX_BACKOFF_SECONDS,
+    };
+    use chrono::NaiveDate;
+    use std::collections::HashMap;
+    use wled_json_api_library::structures::info::Info;
+
+    #[test]
+    fn test_transition_tt() {
+        let mut led_config = LEDBrightnessConfig::default();
+        assert_eq!(led_config.transition_tt(10.), 100);
+        assert_eq!(led_config.transition_tt(2.5), 25);
+        assert_eq!(led_config.transition_tt(60.), 255);
+        led_config.transition_ms = Some(750);
+        assert_eq!(led_config.transition_tt(10.), 8);
+        led_config.transition_ms = Some(0);
+        assert_eq!(led_config.transition_tt(10.), 0);
+    }
+
+    #[test]
+    fn test_local_to_timestamp_dst() {
+        use chrono::TimeZone;
+        use chrono_tz::America::Vancouver;
+        let spring = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        // 02:30 doesn't exist; it should land an hour later at 03:30 PDT.
+        assert_eq!(
+            local_to_timestamp(&Vancouver, spring.and_hms_opt(2, 30, 0).unwrap()),
+            Vancouver
+                .with_ymd_and_hms(2024, 3, 10, 3, 30, 0)
+                .unwrap()
+                .timestamp()
+        );
+        let fall = NaiveDate::from_ymd_opt(2024, 11, 3).unwrap();
+        // 01:30 happens twice; we want the first one, which is still PDT.
+        assert_eq!(
+            local_to_timestamp(&Vancouver, fall.and_hms_opt(1, 30, 0).unwrap()),
+            Vancouver
+                .with_ymd_and_hms(2024, 11, 3, 0, 0, 0)
+                .unwrap()
+                .timestamp()
+                + 90 * 60
+        );
+        // Sunrise is a property of the date, not the zone.
+        assert_eq!(
+            ScheduleTime::Sunrise.to_timestamp_on(spring, &Vancouver, 49., -124.),
+            ScheduleTime::Sunrise.to_timestamp_on(spring, &chrono_tz::UTC, 49., -124.)
+        );
+    }
+
+    #[test]
+    fn test_solar_scheduletimes() {
+        let date = NaiveDate::from_ymd_opt(2024, 10, 1).unwrap();
+        let at = |st: ScheduleTime| st.to_timestamp_on(date, &chrono_tz::UTC, 49., -124.);
+        assert!(at(ScheduleTime::AstronomicalDawn) < at(ScheduleTime::NauticalDawn));
+        assert!(at(ScheduleTime::NauticalDawn) < at(ScheduleTime::CivilDawn));
+        assert!(at(ScheduleTime::CivilDawn) < at(ScheduleTime::Sunrise));
+        assert!(at(ScheduleTime::Sunrise) < at(ScheduleTime::SolarNoon));
+        assert!(at(ScheduleTime::SolarNoon) < at(ScheduleTime::Sunset));
+        assert!(at(ScheduleTime::Sunset) < at(ScheduleTime::CivilDusk));
+        assert!(at(ScheduleTime::CivilDusk) < at(ScheduleTime::NauticalDusk));
+        assert!(at(ScheduleTime::NauticalDusk) < at(ScheduleTime::AstronomicalDusk));
+        assert_eq!(
+            at(ScheduleTime::SunElevation {
+                degrees: -6.,
+                rising: false
+            }),
+            at(ScheduleTime::CivilDusk)
+        );
+        assert_eq!(
+            at(ScheduleTime::Offset(
+                Box::new(ScheduleTime::CivilDusk),
+                -600
+            )),
+            at(ScheduleTime::CivilDusk) - 600
+        );
+        let parsed: ScheduleTime =
+            ron::from_str("Offset(SunElevation(degrees: 10.0, rising: true), 300)")
+                .expect("Failed to parse an offset sun elevation");
+        assert!(matches!(parsed, ScheduleTime::Offset(_, 300)));
+    }
+
+    #[test]
+    fn test_effect_changes_parse() {
+        use crate::types::{NameOrId, WLEDChange};
+        let changes: Vec<WLEDChange> =
+            ron::from_str(r#"[Effect("Rainbow"), Effect(9), Palette("Sunset"), Speed(128)]"#)
+                .unwrap();
+        assert!(
+            matches!(&changes[0], WLEDChange::Effect(NameOrId::Name(name)) if name == "Rainbow")
+        );
+        assert!(matches!(changes[1], WLEDChange::Effect(NameOrId::Id(9))));
+        assert!(
+            matches!(&changes[2], WLEDChange::Palette(NameOrId::Name(name)) if name == "Sunset")
+        );
+        assert!(matches!(changes[3], WLEDChange::Speed(128)));
+
+        let change: WLEDChange = ron::from_str("Nightlight(duration: 30, target_bri: 5)").unwrap();
+        assert!(matches!(
+            change,
+            WLEDChange::Nightlight {
+                duration: 30,
+                target_bri: 5,
+                mode: crate::types::NightlightMode::Fade
+            }
+        ));
+    }
 
     #[test]
     fn test_scheduletime() {
-        let now: chrono::DateTime<chrono::Local> = chrono::Local::now();
-        let st = ScheduleTime::Sunrise;
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("Sunrise+0000 today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
-        println!(
-            "Without TZ time is {:?}",
-            chrono::DateTime::from_timestamp(st_ts as i64, 0).expect("Invalid datetime")
+        use chrono::{NaiveTime, TimeZone};
+        let tz: chrono_tz::Tz = "America/Vancouver".parse().unwrap();
+        let now = tz.with_ymd_and_hms(2024, 6, 7, 9, 30, 0).unwrap();
+        let (lat, lon) = (49., -124.);
+        let (sunrise, sunset) = sunrise::sunrise_sunset(lat, lon, 2024, 6, 7);
+        let at = |st: ScheduleTime| st.to_timestamp(&now, lat, lon) as i64;
+
+        assert_eq!(at(ScheduleTime::Sunrise), sunrise);
+        assert_eq!(at(ScheduleTime::SunriseOffset(3600)), sunrise + 3600);
+        assert_eq!(at(ScheduleTime::SunriseOffset(-3600)), sunrise - 3600);
+        assert_eq!(at(ScheduleTime::Sunset), sunset);
+        assert_eq!(at(ScheduleTime::SunsetOffset(3600)), sunset + 3600);
+        assert_eq!(at(ScheduleTime::SunsetOffset(-3600)), sunset - 3600);
+
+        // Clock times are taken in the zone of `now`, on the same day.
+        let noon = at(ScheduleTime::Time(
+            NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+        ));
+        assert_eq!(
+            noon,
+            tz.with_ymd_and_hms(2024, 6, 7, 12, 0, 0)
+                .unwrap()
+                .timestamp()
         );
-        println!(
-            "My calculated NOW is {:?} and TS is {}",
-            now,
-            now.timestamp()
+        let evening = at(ScheduleTime::Time(
+            NaiveTime::from_hms_opt(21, 50, 0).unwrap(),
+        ));
+        assert_eq!(evening, noon + 9 * 3600 + 50 * 60);
+        assert!(sunrise < noon && noon < sunset && sunset < evening);
+    }
+
+    #[test]
+    fn test_device_health() {
+        let mut health = DeviceHealth::default();
+        assert!(health.should_retry(0));
+        assert_eq!(
+            health.record_failure(100, 10., "timeout".to_string()),
+            Some(HealthState::Online)
+        );
+        assert_eq!(health.state, HealthState::Degraded);
+        assert_eq!(health.retry_at, Some(110));
+        assert!(!health.should_retry(109));
+        assert!(health.should_retry(110));
+        assert_eq!(health.record_failure(110, 10., "timeout".to_string()), None);
+        assert_eq!(health.retry_at, Some(130));
+        assert_eq!(
+            health.record_failure(130, 10., "timeout".to_string()),
+            Some(HealthState::Degraded)
         );
+        assert_eq!(health.state, HealthState::Offline);
+        assert_eq!(health.retry_at, Some(170));
+        for _ in 0..20 {
+            health.record_failure(1000, 10., "timeout".to_string());
+        }
+        assert_eq!(health.backoff_seconds(10.), MAX_BACKOFF_SECONDS);
+        assert_eq!(health.record_success(2000), Some(HealthState::Offline));
+        assert_eq!(health.state, HealthState::Online);
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.last_success, Some(2000));
+        assert!(health.should_retry(2000));
+        assert_eq!(health.record_success(2010), None);
+        assert_eq!(health.record_gone(), Some(HealthState::Online));
+        assert_eq!(health.record_gone(), None);
+        assert_eq!(health.record_success(2020), Some(HealthState::Gone));
+    }
 
-        let st = ScheduleTime::SunriseOffset(3600);
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("Sunrise+3600 today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
-
-        let st = ScheduleTime::SunriseOffset(-3600);
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("Sunrise-3600 today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
-
-        let st = ScheduleTime::Sunset;
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("---\nSunset+0000 today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
-        println!(
-            "Without TZ that time is {:?}",
-            chrono::DateTime::from_timestamp(st_ts as i64, 0).expect("Invalid datetime")
+    #[test]
+    fn test_led_groups() {
+        let group = |members: GroupMatch, priority: i32, max_bri: u8| LEDGroup {
+            members,
+            priority,
+            config: LEDBrightnessConfig {
+                max_bri,
+                ..Default::default()
+            },
+        };
+        let mut config = Config {
+            leds: HashMap::from([(
+                "wled-kitchen-sink._wled._tcp.local.".to_string(),
+                LEDBrightnessConfig {
+                    max_bri: 1,
+                    ..Default::default()
+                },
+            )]),
+            groups: HashMap::from([
+                (
+                    "kitchen".to_string(),
+                    group(GroupMatch::Glob("wled-kitchen-*".to_string()), 0, 2),
+                ),
+                (
+                    "kitchen-regex".to_string(),
+                    group(
+                        GroupMatch::Regex("^wled-kitchen-\\d+\\.".to_string().try_into().unwrap()),
+                        0,
+                        3,
+                    ),
+                ),
+                (
+                    "strips".to_string(),
+                    group(
+                        GroupMatch::Info(InfoMatch {
+                            product: Some("foss".to_string()),
+                            min_leds: Some(100),
+                            ..Default::default()
+                        }),
+                        10,
+                        4,
+                    ),
+                ),
+                (
+                    "porch".to_string(),
+                    group(
+                        GroupMatch::Names(vec!["WLED-Porch._wled._tcp.local.".to_string()]),
+                        0,
+                        5,
+                    ),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let max_bri = |config: &Config, name: &str, info: Option<&Info>| {
+            config
+                .led_config(name, info)
+                .map(|led_config| led_config.max_bri)
+        };
+        // An exact entry beats every group.
+        assert_eq!(
+            max_bri(&config, "wled-kitchen-sink._wled._tcp.local.", None),
+            Some(1)
         );
-        println!(
-            "My calculated NOW is {:?} and TS is {}",
-            now,
-            now.timestamp()
+        assert_eq!(
+            max_bri(&config, "wled-kitchen-hood._wled._tcp.local.", None),
+            Some(2)
         );
-        println!(
-            "That offset would be SUNSET-NOW = {}",
-            st_ts as i64 - now.timestamp()
+        // Same priority: the group name that sorts first.
+        assert_eq!(
+            max_bri(&config, "wled-kitchen-2._wled._tcp.local.", None),
+            Some(2)
+        );
+        assert_eq!(
+            max_bri(&config, "wled-porch._wled._tcp.local.", None),
+            Some(5)
+        );
+        assert_eq!(
+            max_bri(&config, "wled-garage._wled._tcp.local.", None),
+            None
+        );
+
+        let info: Info =
+            serde_json::from_str(r#"{"product": "FOSS", "leds": {"count": 150}}"#).unwrap();
+        assert_eq!(
+            max_bri(&config, "wled-kitchen-2._wled._tcp.local.", Some(&info)),
+            Some(4)
+        );
+        let info: Info =
+            serde_json::from_str(r#"{"product": "FOSS", "leds": {"count": 30}}"#).unwrap();
+        assert_eq!(
+            max_bri(&config, "wled-kitchen-2._wled._tcp.local.", Some(&info)),
+            Some(2)
+        );
+
+        config.groups.get_mut("kitchen-regex").unwrap().priority = 1;
+        assert_eq!(
+            max_bri(&config, "wled-kitchen-2._wled._tcp.local.", None),
+            Some(3)
         );
-        println!("---");
-
-        let st = ScheduleTime::SunsetOffset(3600);
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("Sunset+3600 today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
-
-        let st = ScheduleTime::SunsetOffset(-3600);
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("Sunset-3600 today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
-
-        let st = ScheduleTime::Time(chrono::NaiveTime::from_hms(12, 00, 00));
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("Naive noon today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
-
-        let st = ScheduleTime::Time(chrono::NaiveTime::from_hms(21, 50, 00));
-        let st_ts = st.to_timestamp(49., -124.);
-        let datetime = chrono::DateTime::from_timestamp(st_ts as i64, 0)
-            .expect("Invalid datetime")
-            .with_timezone(&Local::now().timezone());
-        println!("Naive 21:50 today is/was {:?}", datetime);
-        println!("That TS is {}", st_ts);
+        assert!(config.has_info_groups());
     }
 }
@@ -1,14 +1,18 @@
+use crate::backend::LightBackend;
 use crate::types::*;
 use anyhow::{anyhow, Result};
 use chrono::Datelike;
 use fern::colors::{Color, ColoredLevelConfig};
 use fern::log_file;
-use log::{self, error, info, trace, warn};
+use log::{self, debug, info, trace, warn};
 use mdns_sd::ServiceInfo;
+use reqwest::blocking::Client;
 use reqwest::Url;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
-use wled_json_api_library::structures::state::State;
+use std::time::Duration;
+use wled_json_api_library::structures::state::{Nl, Seg, State};
 use wled_json_api_library::wled::Wled;
 
 pub(crate) fn cfg_logging(level: usize, log_path: Option<PathBuf>) {
@@ -77,27 +81,8 @@ fn configure_logging(loglevel: log::LevelFilter, logfile: Option<PathBuf>) {
     fernlog.apply().unwrap();
 }
 
-/// Set the brightness of the given wled device.
-pub fn led_set_preset(wled: &mut WLED, new_preset: u16) -> Result<()> {
-    wled.device.state = Some(State {
-        on: None,
-        bri: None,
-        transition: None,
-        tt: None,
-        ps: Some(new_preset as i32),
-        psave: None,
-        pl: None,
-        nl: None,
-        udpn: None,
-        v: None,
-        rb: None,
-        live: None,
-        lor: None,
-        time: None,
-        mainseg: None,
-        playlist: None,
-        seg: None,
-    });
+/// Sends whatever's been put in `wled.device.state` to the WLED.
+fn flush_wled(wled: &mut WLED) -> Result<()> {
     match wled.device.flush_state() {
         Ok(response) => {
             trace!(
@@ -107,7 +92,7 @@ pub fn led_set_preset(wled: &mut WLED, new_preset: u16) -> Result<()> {
             Ok(())
         }
         Err(err) => {
-            error!(
+            warn!(
                 "    - Failed to update WLED: '{}' with error: {:?}",
                 &wled.name, err
             );
@@ -119,40 +104,47 @@ pub fn led_set_preset(wled: &mut WLED, new_preset: u16) -> Result<()> {
         }
     }
 }
+
+/// Set the brightness of the given wled device.
+pub fn led_set_preset(wled: &mut WLED, new_preset: u16, tt: u8) -> Result<()> {
+    wled.device.state = Some(State {
+        on: None,
+        bri: None,
+        transition: None,
+        tt: Some(tt),
+        ps: Some(new_preset as i32),
+        psave: None,
+        pl: None,
+        nl: None,
+        udpn: None,
+        v: None,
+        rb: None,
+        live: None,
+        lor: None,
+        time: None,
+        mainseg: None,
+        playlist: None,
+        seg: None,
+    });
+    flush_wled(wled)
+}
 pub fn led_set_power(wled: &mut WLED, power: bool) -> Result<()> {
     wled.device.state = Some(State {
         on: Some(power),
         ..Default::default()
     });
-    match wled.device.flush_state() {
-        Ok(response) => {
-            trace!(
-                "    - HTTP response: {:?}",
-                response.text().unwrap_or("UNKNOWN ERROR".to_string())
-            );
-            Ok(())
-        }
-        Err(err) => {
-            error!(
-                "    - Failed to update WLED: '{}' with error: {:?}",
-                &wled.name, err
-            );
-            Err(anyhow!(
-                "Failed to update wled {} with error {:?}",
-                &wled.name,
-                err
-            ))
-        }
-    }
+    flush_wled(wled)
 }
 
-/// Set the brightness of the given wled device.
-pub fn led_set_brightness(wled: &mut WLED, new_bri: u8) -> Result<()> {
+/// Set the brightness of the given wled device, fading over `tt` (in units
+/// of 100ms). We use `tt` rather than `transition` so the device's own
+/// default fade, as set in its UI, is left alone.
+pub fn led_set_brightness(wled: &mut WLED, new_bri: u8, tt: u8) -> Result<()> {
     wled.device.state = Some(State {
         on: if new_bri > 0 { Some(true) } else { Some(false) },
         bri: Some(new_bri),
         transition: None,
-        tt: None,
+        tt: Some(tt),
         ps: None,
         psave: None,
         pl: None,
@@ -167,76 +159,435 @@ pub fn led_set_brightness(wled: &mut WLED, new_bri: u8) -> Result<()> {
         playlist: None,
         seg: None,
     });
-    match wled.device.flush_state() {
-        Ok(response) => {
-            trace!(
-                "    - HTTP response: {:?}",
-                response.text().unwrap_or("UNKNOWN ERROR".to_string())
-            );
-            Ok(())
-        }
-        Err(err) => {
-            error!(
-                "    - Failed to update WLED: '{}' with error: {:?}",
-                &wled.name, err
-            );
-            Err(anyhow!(
-                "Failed to update wled {} with error {:?}",
-                &wled.name,
-                err
-            ))
-        }
+    flush_wled(wled)
+}
+
+/// Starts a playlist (which WLED keeps in a preset slot), or stops whatever
+/// playlist is running.
+pub fn led_set_playlist(wled: &mut WLED, playlist: PlaylistState) -> Result<()> {
+    wled.device.state = Some(match playlist {
+        PlaylistState::Running(id) => State {
+            ps: Some(id as i32),
+            ..Default::default()
+        },
+        PlaylistState::Stopped => State {
+            pl: Some(-1),
+            ..Default::default()
+        },
+    });
+    flush_wled(wled)
+}
+
+/// Switches the WLED on and starts its nightlight timer.
+pub fn led_set_nightlight(wled: &mut WLED, nightlight: &NightlightState) -> Result<()> {
+    wled.device.state = Some(State {
+        on: Some(true),
+        nl: Some(Nl {
+            on: Some(true),
+            dur: Some(nightlight.duration),
+            mode: Some(nightlight.mode.as_u8()),
+            tbri: Some(nightlight.target_bri),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    flush_wled(wled)
+}
+
+/// Sends the segment-level parts of `state` (color, effect and so on) to the
+/// first segment, which is the whole strip unless it has been split up.
+pub fn led_set_primary_seg(wled: &mut WLED, state: &LEDState, tt: u8) -> Result<()> {
+    let seg = build_seg(wled, state)?;
+    led_set_segments(wled, vec![seg], tt)
+}
+
+/// Sends the results of `calc_segments_scheduled` to their segments.
+pub fn led_set_segment_states(
+    wled: &mut WLED,
+    segments: &[(u8, u8, LEDState)],
+    tt: u8,
+) -> Result<()> {
+    let mut segs: Vec<Seg> = Vec::new();
+    for (id, bri, state) in segments {
+        segs.push(Seg {
+            id: Some(*id as i16),
+            bri: Some(*bri),
+            on: state.power,
+            ..build_seg(wled, state)?
+        });
     }
+    led_set_segments(wled, segs, tt)
 }
 
-pub fn update_wled_cache(info: &ServiceInfo, found_wled: &mut HashMap<String, WLED>) -> Result<()> {
-    let full_name = info.get_fullname().to_string();
-    let short_name = info.get_hostname().to_string();
-    let old_wled = found_wled.get(&full_name);
-    if old_wled.is_none()
-        || (old_wled.is_some() && !info.get_addresses().contains(&old_wled.unwrap().address))
-    {
-        if old_wled.is_some() {
+/// Sends per-segment settings, leaving the device-wide state alone.
+pub fn led_set_segments(wled: &mut WLED, segments: Vec<Seg>, tt: u8) -> Result<()> {
+    wled.device.state = Some(State {
+        tt: Some(tt),
+        seg: Some(segments),
+        ..Default::default()
+    });
+    flush_wled(wled)
+}
+
+/// The parts of a snapshot that put a WLED back how it was. Presets and
+/// playlists are left out, since applying one would paint over the segments.
+pub fn restorable_state(snapshot: &State) -> State {
+    State {
+        on: snapshot.on,
+        bri: snapshot.bri,
+        mainseg: snapshot.mainseg,
+        seg: snapshot.seg.clone(),
+        // Leave realtime mode, in case LedFx left it on.
+        live: Some(false),
+        lor: Some(0),
+        ..Default::default()
+    }
+}
+
+/// Puts a WLED back to a snapshot of its state.
+pub fn led_restore_state(wled: &mut WLED, snapshot: &State) -> Result<()> {
+    wled.device.state = Some(restorable_state(snapshot));
+    flush_wled(wled)
+}
+
+/// The HTTP client shared by every WLED. The timeouts keep one unplugged
+/// controller from holding up a whole cycle.
+pub fn http_client(config: &Config) -> Result<Client> {
+    Ok(Client::builder()
+        .gzip(true)
+        .connect_timeout(Duration::from_millis(config.http_connect_timeout_ms))
+        .timeout(Duration::from_millis(config.http_timeout_ms))
+        .build()?)
+}
+
+/// A WLED handle that talks through `client`. Unlike `Wled::try_from_url`,
+/// this doesn't touch the network.
+pub fn wled_device(url: Url, client: &Client) -> Wled {
+    Wled {
+        effects: None,
+        palettes: None,
+        state: None,
+        info: None,
+        cfg: None,
+        live: None,
+        nodes: None,
+        net: None,
+        client: client.clone(),
+        url,
+    }
+}
+
+pub fn update_wled_cache(
+    info: &ServiceInfo,
+    config: &Config,
+    found_wled: &mut HashMap<String, WLED>,
+    client: &Client,
+) -> Result<()> {
+    let addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+    register_wled(
+        info.get_fullname(),
+        &addresses,
+        info.get_port(),
+        config.prefer_address,
+        found_wled,
+        client,
+    )
+}
+
+/// Orders `addresses` to be tried: the preferred family first, then by
+/// address so the choice doesn't change from one announcement to the next.
+pub fn sort_addresses(addresses: &[IpAddr], prefer: AddressFamily) -> Vec<IpAddr> {
+    let mut addresses = addresses.to_vec();
+    addresses.sort_by_key(|address| (!prefer.matches(address), *address));
+    addresses.dedup();
+    addresses
+}
+
+/// The base URL of a WLED at `address`, with IPv6 addresses bracketed.
+pub fn wled_url(address: IpAddr, port: u16) -> Result<Url> {
+    Ok(Url::parse(&format!(
+        "http://{}/",
+        SocketAddr::new(address, port)
+    ))?)
+}
+
+/// Adds (or moves) the WLED `full_name` to the first of `addresses` that
+/// answers. A healthy WLED that's still at one of them, in the preferred
+/// family if that's on offer, is left where it is.
+pub fn register_wled(
+    full_name: &str,
+    addresses: &[IpAddr],
+    port: u16,
+    prefer: AddressFamily,
+    found_wled: &mut HashMap<String, WLED>,
+    client: &Client,
+) -> Result<()> {
+    let addresses = sort_addresses(addresses, prefer);
+    if let Some(old_wled) = found_wled.get(full_name) {
+        let best_family = addresses
+            .first()
+            .is_some_and(|best| best.is_ipv4() == old_wled.address.is_ipv4());
+        if old_wled.health.state == HealthState::Online
+            && addresses.contains(&old_wled.address)
+            && best_family
+        {
+            return Ok(());
+        }
+        if !addresses.contains(&old_wled.address) {
             warn!("WLED '{}' may have changed IPs. Updating.", full_name);
         }
-        // let ip_addr: Option<IpAddr> = None;
-        for try_ip in info.get_addresses() {
-            let url: Url =
-                Url::try_from(format!("http://{}:{}/", try_ip, info.get_port()).as_str())
-                    .unwrap_or_else(|_| {
-                        panic!("Invalid addr/port: {}:{}", try_ip, info.get_port())
-                    });
-            info!("Found WLED {} at: {}", &short_name, &url);
-            let mut wled: Wled = Wled::try_from_url(&url).unwrap();
-            // info!("new wled: {wled:?}");
-            match wled.get_state_from_wled() {
-                Ok(()) => {
-                    if let Some(state) = &wled.state {
-                        // info!("WLED CFG: {:?}", &wled.cfg);
-                        found_wled.insert(
-                            full_name.to_string(),
-                            WLED {
-                                state: Some(state.clone()),
-                                address: *try_ip,
-                                name: info.get_fullname().to_string(),
-                                device: wled,
-                            },
-                        );
-                        return Ok(());
+    }
+    for try_ip in &addresses {
+        let url = match wled_url(*try_ip, port) {
+            Ok(url) => url,
+            Err(err) => {
+                warn!(
+                    "Bad address for WLED {}: {}:{} ({})",
+                    full_name, try_ip, port, err
+                );
+                continue;
+            }
+        };
+        info!("Found WLED {} at: {}", full_name, &url);
+        let mut wled: Wled = wled_device(url, client);
+        match wled.get_state_from_wled() {
+            Ok(()) => {
+                if let Some(state) = wled.state.clone() {
+                    // Only needed for groups that match on it.
+                    if let Err(err) = wled.get_info_from_wled() {
+                        debug!("Couldn't read info from WLED {}: {:?}", full_name, err);
                     }
-                }
-                Err(the_error) => {
-                    warn!(
-                        "Failed to read config from WLED: {} -> {}",
-                        full_name, the_error
+                    // Same light, new address: remember what we last sent it.
+                    let (memory, mut health) = found_wled
+                        .remove(full_name)
+                        .map(|old| (old.memory, old.health))
+                        .unwrap_or_default();
+                    if let Some(old) = health.record_success(chrono::Utc::now().timestamp()) {
+                        info!("WLED '{}' is back online (was {:?}).", full_name, old);
+                    }
+                    found_wled.insert(
+                        full_name.to_string(),
+                        WLED {
+                            state: Some(state),
+                            address: *try_ip,
+                            name: full_name.to_string(),
+                            device: wled,
+                            memory,
+                            health,
+                            live: None,
+                        },
                     );
+                    return Ok(());
                 }
             }
+            Err(the_error) => {
+                warn!(
+                    "Failed to read config from WLED: {} -> {}",
+                    full_name, the_error
+                );
+            }
         }
-        return Err(anyhow!("Could not register WLED: {}", info.get_fullname()));
     }
+    Err(anyhow!("Could not register WLED: {}", full_name))
+}
+
+/// Marks a WLED that mDNS says has left the network (or whose records have
+/// expired). It's picked up again when it's next announced.
+pub fn mark_wled_gone(full_name: &str, found_wled: &mut HashMap<String, WLED>) {
+    if let Some(wled) = found_wled.get_mut(full_name) {
+        // Stops its live reader, which would otherwise keep reconnecting.
+        wled.live = None;
+        if wled.health.record_gone().is_some() {
+            info!("WLED '{}' has left the network.", full_name);
+        }
+    }
+}
+
+/// Registers the WLEDs listed under `devices` in the config. One that
+/// doesn't answer is still added at its first address, so it gets retried
+/// (with backoff) like any other failing device.
+pub fn register_static_devices(
+    config: &Config,
+    found_wled: &mut HashMap<String, WLED>,
+    client: &Client,
+) {
+    for (name, device) in &config.devices {
+        let addresses: Vec<IpAddr> = match (device.host.as_str(), device.port).to_socket_addrs() {
+            Ok(addrs) => sort_addresses(
+                &addrs.map(|addr| addr.ip()).collect::<Vec<_>>(),
+                config.prefer_address,
+            ),
+            Err(err) => {
+                warn!(
+                    "Couldn't resolve WLED '{}' ({}): {}",
+                    name, device.host, err
+                );
+                continue;
+            }
+        };
+        if let Err(err) = register_wled(
+            name,
+            &addresses,
+            device.port,
+            config.prefer_address,
+            found_wled,
+            client,
+        ) {
+            warn!("{}", err);
+            let Some(address) = addresses.first().copied() else {
+                continue;
+            };
+            if found_wled.contains_key(name) {
+                continue;
+            }
+            let Ok(url) = wled_url(address, device.port) else {
+                continue;
+            };
+            found_wled.insert(
+                name.clone(),
+                WLED {
+                    state: None,
+                    address,
+                    name: name.clone(),
+                    device: wled_device(url, client),
+                    memory: LedMemory::default(),
+                    health: DeviceHealth::default(),
+                    live: None,
+                },
+            );
+        }
+    }
+}
+
+/// Whether an mDNS announcement is for a WLED that's listed under `devices`,
+/// by name or by address. Those are managed from the config alone.
+pub fn is_static_device(
+    config: &Config,
+    found_wled: &HashMap<String, WLED>,
+    info: &ServiceInfo,
+) -> bool {
+    config.devices.contains_key(info.get_fullname())
+        || config.devices.keys().any(|name| {
+            found_wled
+                .get(name)
+                .is_some_and(|wled| info.get_addresses().contains(&wled.address))
+        })
+}
+
+/// Scales a scheduled brightness (0.0-1.0) into the LED's min_bri..max_bri range,
+/// using the LED's configured response curve.
+pub fn calc_led_bri(led_config: &LEDBrightnessConfig, bri_pc: f32) -> u8 {
+    calc_bri(
+        led_config.min_bri,
+        led_config.max_bri,
+        &led_config.curve,
+        bri_pc,
+    )
+}
+
+fn calc_bri(min_bri: u8, max_bri: u8, curve: &BrightnessCurve, bri_pc: f32) -> u8 {
+    let low = min_bri as f32;
+    let high = max_bri as f32;
+    (low + curve.apply(bri_pc) * (high - low))
+        .round()
+        .clamp(0., 255.) as u8
+}
+
+/// Works out the state of each of an LED's per-segment schedules, with the
+/// brightness already scaled for the segment, in segment ID order. Segments
+/// whose schedule can't be found are left out.
+pub(crate) fn calc_segments_scheduled(
+    schedules: &Schedules,
+    led_config: &LEDBrightnessConfig,
+    now: i64,
+) -> Vec<(u8, u8, LEDState)> {
+    let mut ids: Vec<&u8> = led_config.segments.keys().collect();
+    ids.sort();
+    ids.into_iter()
+        .filter_map(|id| {
+            let seg_config = &led_config.segments[id];
+            let state = schedules.get(&seg_config.schedule)?.state_at(now)?;
+            let bri = calc_bri(
+                seg_config.min_bri,
+                seg_config.max_bri,
+                &seg_config.curve,
+                state.bri,
+            );
+            Some((*id, bri, state))
+        })
+        .collect()
+}
+
+/// The names in an effect or palette list. The library keeps the list
+/// itself private, but it serializes as a plain array of strings.
+fn name_list<T: serde::Serialize>(list: &Option<T>) -> Vec<String> {
+    list.as_ref()
+        .and_then(|list| serde_json::to_value(list).ok())
+        .and_then(|value| serde_json::from_value(value).ok())
+        .unwrap_or_default()
+}
+
+fn find_name(names: &[String], wanted: &NameOrId) -> Option<u16> {
+    match wanted {
+        NameOrId::Id(id) => Some(*id),
+        NameOrId::Name(name) => names
+            .iter()
+            .position(|candidate| candidate.eq_ignore_ascii_case(name))
+            .map(|idx| idx as u16),
+    }
+}
 
-    Ok(())
+/// Turns an effect name into the ID this particular WLED uses for it,
+/// fetching (and keeping) the device's effect list the first time.
+/// Unknown names are logged and skipped rather than treated as failures.
+pub fn resolve_effect(wled: &mut WLED, effect: &NameOrId) -> Result<Option<u16>> {
+    if matches!(effect, NameOrId::Name(_)) && wled.device.effects.is_none() {
+        wled.device
+            .get_effects_from_wled()
+            .map_err(|err| anyhow!("Failed to get effects from {}: {:?}", &wled.name, err))?;
+    }
+    let found = find_name(&name_list(&wled.device.effects), effect);
+    if found.is_none() {
+        warn!("WLED '{}' has no effect called {:?}.", &wled.name, effect);
+    }
+    Ok(found)
+}
+
+/// As `resolve_effect`, but for palettes.
+pub fn resolve_palette(wled: &mut WLED, palette: &NameOrId) -> Result<Option<u16>> {
+    if matches!(palette, NameOrId::Name(_)) && wled.device.palettes.is_none() {
+        wled.device
+            .get_palettes_from_wled()
+            .map_err(|err| anyhow!("Failed to get palettes from {}: {:?}", &wled.name, err))?;
+    }
+    let found = find_name(&name_list(&wled.device.palettes), palette);
+    if found.is_none() {
+        warn!("WLED '{}' has no palette called {:?}.", &wled.name, palette);
+    }
+    Ok(found)
+}
+
+/// Builds a `seg` entry carrying the segment-level parts of `state` (color,
+/// effect and so on). Brightness, power and the segment ID are left for the
+/// caller, since they mean different things for the device and for segments.
+fn build_seg(wled: &mut WLED, state: &LEDState) -> Result<Seg> {
+    let fx = match &state.effect {
+        Some(effect) => resolve_effect(wled, effect)?,
+        None => None,
+    };
+    let pal = match &state.palette {
+        Some(palette) => resolve_palette(wled, palette)?,
+        None => None,
+    };
+    Ok(Seg {
+        cct: state.cct,
+        col: state.color.clone().map(|color| vec![color]),
+        fx,
+        pal,
+        sx: state.speed,
+        ix: state.intensity,
+        ..Default::default()
+    })
 }
 
 /// Calculates how much we should dim (from 0.0 as no dimming, to 1.0 as fully dimmed)
@@ -292,139 +643,396 @@ pub fn calc_dim_pc(
     }
 }
 
-#[allow(unused)]
-pub(crate) fn calc_led_state_scheduled(
-    now: chrono::DateTime<chrono::Local>,
-    lat: f64,
-    lon: f64,
-    schedule: &Vec<WLEDScheduleItem>,
-) -> (f32, Option<u16>, Option<bool>) {
-    // Generate initial event lists.
-    let mut bri_ev: Vec<(u64, f32)> = schedule
-        .clone()
-        .iter()
-        .filter(|i| matches!(i.change, WLEDChange::Brightness(_)))
-        .map(|i| {
-            (
-                i.time.to_timestamp(lat, lon),
-                if let WLEDChange::Brightness(bri) = i.change {
-                    bri
-                } else {
-                    0.
-                },
-            )
-        })
-        .collect();
-    let mut pre_ev: Vec<(u64, u16)> = schedule
-        .clone()
-        .iter()
-        .filter(|i| matches!(i.change, WLEDChange::Preset(_)))
-        .map(|i| {
-            (
-                i.time.to_timestamp(lat, lon),
-                if let WLEDChange::Preset(pre) = i.change {
-                    pre
-                } else {
-                    panic!("WTF?! Unreachable preset.");
-                    1 // Note, this is hopefully unreachable.
-                },
-            )
-        })
-        .collect();
-    let mut power_ev: Vec<(u64, bool)> = schedule
-        .clone()
-        .iter()
-        .filter(|i| matches!(i.change, WLEDChange::Power(_)))
-        .map(|i| {
-            (
-                i.time.to_timestamp(lat, lon),
-                if let WLEDChange::Power(power) = i.change {
-                    power
-                } else {
-                    panic!("WTF?! Unreachable preset.");
-                    false // Note, this is hopefully unreachable.
-                },
-            )
-        })
-        .collect();
+/// How many days either side of today we pull keyframes from. A week means
+/// weekday/weekend-only items always have a neighbour to step or blend from.
+pub(crate) const SCHEDULE_WINDOW_DAYS: i64 = 7;
 
-    // Add offsets to beginning and end...
-    let mut tmp_bri_ev = bri_ev.clone();
-    let mut tmp_pre_ev = pre_ev.clone();
-    if let Some(last) = bri_ev.last() {
-        tmp_bri_ev.insert(0, (last.0 - (24 * 3600), last.1));
+/// Compares what a WLED reports with what we last asked of it, and describes
+/// the difference if someone has changed it since. Only what we set is
+/// compared: power and brightness always, and the preset, color, effect and
+/// so on when the schedule gave them.
+pub(crate) fn manual_change(
+    led_config: &LEDBrightnessConfig,
+    last: &LEDState,
+    current: &State,
+) -> Option<String> {
+    if last.power == Some(false) {
+        return match current.on {
+            Some(true) => Some("switched on".to_string()),
+            _ => None,
+        };
     }
-    if let Some(first) = bri_ev.first() {
-        tmp_bri_ev.push((first.0 + (24 * 3600), first.1));
+    let expected_bri = calc_led_bri(led_config, last.bri);
+    if current.on == Some(false) && expected_bri > 0 {
+        return Some("switched off".to_string());
     }
-    bri_ev = tmp_bri_ev;
-    if let Some(last) = pre_ev.last() {
-        tmp_pre_ev.insert(0, (last.0 - (24 * 3600), last.1));
+    // A running playlist moves through presets by itself, and those usually
+    // bring their own brightness, colors and effects.
+    if matches!(last.playlist, Some(PlaylistState::Running(_))) {
+        return None;
     }
-    if let Some(first) = pre_ev.first() {
-        tmp_pre_ev.push((first.0 + (24 * 3600), first.1));
+    match (current.on, current.bri) {
+        (Some(true), Some(bri)) if bri != expected_bri => {
+            return Some(format!("brightness is {} instead of {}", bri, expected_bri))
+        }
+        _ => (),
     }
-    pre_ev = tmp_pre_ev;
-
-    // Same for presets
-
-    // Then we determine current time and where that sits.
-    let now: i64 = now.timestamp();
-
-    if bri_ev.len() < 2 || pre_ev.len() == 1 {
-        // Note that an event length of "1" is the only invalid
-        // preset config. No changes is fine, and more than 1 is always valid.
-        error!("Invalid schedule found: {:?}.", &schedule);
-        return (0., None, None); // This is fucked. Should always have a couple entries.
+    // WLED reports -1 once our colors go on top of a preset, so only another
+    // preset counts.
+    if let (Some(want), Some(have)) = (last.preset, current.ps) {
+        if have > 0 && have != want as i32 {
+            return Some(format!("preset is {} instead of {}", have, want));
+        }
+    }
+    // With segments configured, the main segment gets their settings too.
+    if !led_config.segments.is_empty() {
+        return None;
+    }
+    let segs = current.seg.as_ref()?;
+    let main = current.mainseg.unwrap_or(0) as i16;
+    let seg = segs
+        .iter()
+        .find(|seg| seg.id == Some(main))
+        .or(segs.first())?;
+    let differs = |field: &str, want: Option<u16>, have: Option<u16>| match (want, have) {
+        (Some(want), Some(have)) if want != have => {
+            Some(format!("{} is {} instead of {}", field, have, want))
+        }
+        _ => None,
+    };
+    let by_id = |want: &Option<NameOrId>| match want {
+        // Names are looked up on the WLED as they're sent; we don't keep the ID.
+        Some(NameOrId::Id(id)) => Some(*id),
+        _ => None,
+    };
+    if let (Some(want), Some(have)) = (&last.color, seg.col.as_ref().and_then(|col| col.first())) {
+        let channels = want.len().min(have.len());
+        if want[..channels] != have[..channels] {
+            return Some(format!("color is {:?} instead of {:?}", have, want));
+        }
     }
+    // WLED keeps CCT as 0-255, so Kelvin is converted as WLED does it.
+    differs(
+        "cct",
+        last.cct
+            .map(|kelvin| (kelvin.clamp(1900, 10091) - 1900) >> 5),
+        seg.cct,
+    )
+    .or_else(|| differs("effect", by_id(&last.effect), seg.fx))
+    .or_else(|| differs("palette", by_id(&last.palette), seg.pal))
+    .or_else(|| differs("speed", last.speed.map(u16::from), seg.sx.map(u16::from)))
+    .or_else(|| {
+        differs(
+            "intensity",
+            last.intensity.map(u16::from),
+            seg.ix.map(u16::from),
+        )
+    })
+}
 
-    let mut out: f32 = 0.;
+/// Checks a light's current state against what we last sent.
+pub fn led_check_manual_change(
+    light: &mut dyn LightBackend,
+    led_config: &LEDBrightnessConfig,
+    last: &LEDState,
+) -> Result<Option<String>> {
+    Ok(manual_change(led_config, last, &light.read_state()?))
+}
 
-    for i in 0..(bri_ev.len() - 1) {
-        let before = bri_ev
-            .get(i)
-            .expect("Failed to index into a known position.");
-        let after = bri_ev
-            .get(i + 1)
-            .expect("Failed to index into a known position.");
+/// Finds where `now` falls between two keyframes and blends between them,
+/// using the interpolation set on the earlier one. Returns None if `now`
+/// isn't covered by the list.
+fn interpolate_events(events: &[(u64, f32, Interpolation)], now: i64) -> Option<f32> {
+    events.windows(2).find_map(|pair| {
+        let (before, after) = (&pair[0], &pair[1]);
         if now >= before.0 as i64 && now <= after.0 as i64 {
-            let delta_pc = (now as f32 - before.0 as f32) / (after.0 as f32 - before.0 as f32);
-            let delta_amt = after.1 - before.1;
-            out = before.1 + delta_pc * delta_amt;
+            let delta_pc = if after.0 > before.0 {
+                // Subtract before converting; f32 can't resolve seconds at unix-epoch scale.
+                (now - before.0 as i64) as f32 / (after.0 - before.0) as f32
+            } else {
+                1.
+            };
+            Some(before.2.interpolate(before.1, after.1, delta_pc))
+        } else {
+            None
+        }
+    })
+}
+/// Finds the value set by the most recent keyframe at or before `now`.
+/// Finds the value set by the most recent keyframe before `now`.
+fn step_events<T: Copy>(events: &[(u64, T)], now: i64) -> Option<T> {
+    events
+        .windows(2)
+        .find(|pair| now >= pair[0].0 as i64 && now < pair[1].0 as i64)
+        .map(|pair| pair[0].1)
+}
+
+/// A schedule's keyframes, resolved to timestamps for the days around one
+/// date and put in time order. Resolving means working out sun times for
+/// every item on every one of those days, so it's done once (per schedule
+/// per cycle) and then asked about as often as needed.
+pub struct ResolvedSchedule<'a> {
+    schedule: &'a [WLEDScheduleItem],
+    keyframes: Vec<(u64, &'a WLEDScheduleItem)>,
+}
 
-            break;
+impl<'a> ResolvedSchedule<'a> {
+    pub fn new<Tz: chrono::TimeZone>(
+        schedule: &'a [WLEDScheduleItem],
+        date: chrono::NaiveDate,
+        tz: &Tz,
+        lat: f64,
+        lon: f64,
+    ) -> ResolvedSchedule<'a> {
+        // Resolve every item that applies on the days around `date` against
+        // its own date. Neighbouring days may follow a different day type (e.g.
+        // Friday night into a Saturday morning), so we can't just copy one list.
+        let mut keyframes: Vec<(u64, &WLEDScheduleItem)> = Vec::new();
+        for day_offset in -SCHEDULE_WINDOW_DAYS..=SCHEDULE_WINDOW_DAYS {
+            let date = date + chrono::Duration::days(day_offset);
+            keyframes.extend(
+                schedule
+                    .iter()
+                    .filter(|i| i.is_active_on(date))
+                    .map(|i| (i.time.to_timestamp_on(date, tz, lat, lon), i)),
+            );
+        }
+        // Items needn't be listed in time order, and sun times can pass fixed
+        // times as the seasons change. The sort is stable, so ties keep their
+        // listed order.
+        keyframes.sort_by_key(|(ts, _)| *ts);
+        ResolvedSchedule {
+            schedule,
+            keyframes,
         }
     }
 
-    let mut preset_out: Option<u16> = None;
-    if pre_ev.len() >= 2 {
-        for i in 0..(pre_ev.len() - 1) {
-            let before = pre_ev
-                .get(i)
-                .expect("Failed to index into a known position.");
-            let after = pre_ev
-                .get(i + 1)
-                .expect("Failed to index into a known position.");
-            if now >= before.0 as i64 && now <= after.0 as i64 {
-                preset_out = Some(before.1);
-                break;
-            }
+    /// The timestamp of the first keyframe after `now`.
+    pub fn next_keyframe_after(&self, now: i64) -> Option<u64> {
+        self.keyframes
+            .iter()
+            .map(|(ts, _)| *ts)
+            .find(|ts| *ts as i64 > now)
+    }
+
+    /// The state the schedule calls for at `now`, which should be on or near
+    /// the date it was resolved for. None if the schedule doesn't cover `now`:
+    /// there are no brightness keyframes either side of it to blend between,
+    /// or the schedule has a single preset item.
+    pub fn state_at(&self, now: i64) -> Option<LEDState> {
+        let keyframes = &self.keyframes;
+
+        // Generate event lists.
+        let bri_ev: Vec<(u64, f32, Interpolation)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Brightness(bri) => Some((*ts, bri, i.interpolation)),
+                _ => None,
+            })
+            .collect();
+        let pre_ev: Vec<(u64, u16)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Preset(pre) => Some((*ts, pre)),
+                _ => None,
+            })
+            .collect();
+        let power_ev: Vec<(u64, bool)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Power(power) => Some((*ts, power)),
+                _ => None,
+            })
+            .collect();
+        let cct_ev: Vec<(u64, f32, Interpolation)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Cct(kelvin) => Some((*ts, kelvin as f32, i.interpolation)),
+                _ => None,
+            })
+            .collect();
+        let effect_ev: Vec<(u64, &NameOrId)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match &i.change {
+                WLEDChange::Effect(effect) => Some((*ts, effect)),
+                _ => None,
+            })
+            .collect();
+        let palette_ev: Vec<(u64, &NameOrId)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match &i.change {
+                WLEDChange::Palette(palette) => Some((*ts, palette)),
+                _ => None,
+            })
+            .collect();
+        let speed_ev: Vec<(u64, f32, Interpolation)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Speed(speed) => Some((*ts, speed as f32, i.interpolation)),
+                _ => None,
+            })
+            .collect();
+        let intensity_ev: Vec<(u64, f32, Interpolation)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Intensity(intensity) => Some((*ts, intensity as f32, i.interpolation)),
+                _ => None,
+            })
+            .collect();
+        let playlist_ev: Vec<(u64, PlaylistState)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Playlist(id) => Some((*ts, PlaylistState::Running(id))),
+                WLEDChange::StopPlaylist => Some((*ts, PlaylistState::Stopped)),
+                _ => None,
+            })
+            .collect();
+        let nightlight_ev: Vec<NightlightState> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match i.change {
+                WLEDChange::Nightlight {
+                    duration,
+                    target_bri,
+                    mode,
+                } => Some(NightlightState {
+                    started: *ts,
+                    duration,
+                    target_bri,
+                    mode,
+                }),
+                _ => None,
+            })
+            .collect();
+        let color_ev: Vec<(u64, &Vec<u8>, Interpolation)> = keyframes
+            .iter()
+            .filter_map(|(ts, i)| match &i.change {
+                WLEDChange::Color(color) => Some((*ts, color, i.interpolation)),
+                _ => None,
+            })
+            .collect();
+
+        let single_preset = self
+            .schedule
+            .iter()
+            .filter(|item| matches!(item.change, WLEDChange::Preset(_)))
+            .count()
+            == 1;
+        if single_preset {
+            return None;
         }
+        let bri = interpolate_events(&bri_ev, now)?;
+
+        // Colors blend channel by channel. If any keyframe has a white channel
+        // we send one throughout, treating plain RGB keyframes as W=0.
+        let color_channels = color_ev
+            .iter()
+            .map(|(_, color, _)| color.len().clamp(3, 4))
+            .max()
+            .unwrap_or(3);
+        let color_out: Option<Vec<u8>> = (0..color_channels)
+            .map(|channel| {
+                let channel_ev: Vec<(u64, f32, Interpolation)> = color_ev
+                    .iter()
+                    .map(|(ts, color, interpolation)| {
+                        (
+                            *ts,
+                            *color.get(channel).unwrap_or(&0) as f32,
+                            *interpolation,
+                        )
+                    })
+                    .collect();
+                interpolate_events(&channel_ev, now)
+                    .map(|value| value.round().clamp(0., 255.) as u8)
+            })
+            .collect();
+
+        Some(LEDState {
+            bri,
+            preset: step_events(&pre_ev, now),
+            power: step_events(&power_ev, now),
+            cct: interpolate_events(&cct_ev, now).map(|kelvin| kelvin.round() as u16),
+            color: color_out,
+            effect: step_events(&effect_ev, now).cloned(),
+            palette: step_events(&palette_ev, now).cloned(),
+            speed: interpolate_events(&speed_ev, now).map(|speed| speed.round() as u8),
+            intensity: interpolate_events(&intensity_ev, now)
+                .map(|intensity| intensity.round() as u8),
+            playlist: step_events(&playlist_ev, now),
+            // Only the latest nightlight counts, and only until it has run its course.
+            nightlight: nightlight_ev
+                .into_iter()
+                .filter(|nightlight| nightlight.started as i64 <= now)
+                .max_by_key(|nightlight| nightlight.started)
+                .filter(|nightlight| {
+                    now < nightlight.started as i64 + nightlight.duration as i64 * 60
+                }),
+        })
     }
+}
 
-    let mut power_out: Option<bool> = None;
+/// Every schedule in the config, resolved around one moment. Built once a
+/// cycle and shared by every device.
+pub struct Schedules<'a> {
+    resolved: HashMap<&'a str, ResolvedSchedule<'a>>,
+}
 
+impl<'a> Schedules<'a> {
+    pub fn new<Tz: chrono::TimeZone>(
+        config: &'a Config,
+        now: chrono::DateTime<Tz>,
+    ) -> Schedules<'a> {
+        let date = now.date_naive();
+        let tz = now.timezone();
+        Schedules {
+            resolved: config
+                .schedule
+                .iter()
+                .map(|(name, schedule)| {
+                    (
+                        name.as_str(),
+                        ResolvedSchedule::new(
+                            schedule,
+                            date,
+                            &tz,
+                            config.lat as f64,
+                            config.lon as f64,
+                        ),
+                    )
+                })
+                .collect(),
+        }
+    }
 
-    (out, preset_out, power_out)
+    /// The resolved schedule `spec` refers to, as `Config::led_schedule`.
+    pub fn get(&self, spec: &LEDScheduleSpec) -> Option<&ResolvedSchedule<'a>> {
+        match spec {
+            LEDScheduleSpec::Default => self.resolved.get("default"),
+            LEDScheduleSpec::ByName(name) => self.resolved.get(name.as_str()),
+            LEDScheduleSpec::None => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::types::{ScheduleTime, WLEDChange, WLEDSchedule};
-    use chrono::{DateTime, Datelike, Local, NaiveTime};
+    use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone};
     use fern::colors::{Color, ColoredLevelConfig};
 
+    /// The state `schedule` calls for at `now`, or the default (off) state
+    /// if it has nothing to say.
+    fn calc_led_state_scheduled<Tz: TimeZone>(
+        now: DateTime<Tz>,
+        lat: f64,
+        lon: f64,
+        schedule: &[WLEDScheduleItem],
+    ) -> LEDState {
+        ResolvedSchedule::new(schedule, now.date_naive(), &now.timezone(), lat, lon)
+            .state_at(now.timestamp())
+            .unwrap_or_default()
+    }
+
     #[test]
     fn test_calc_dimming_schedule() {
         let dispatch = fern::Dispatch::new();
@@ -452,49 +1060,398 @@ mod test {
         let today: chrono::DateTime<chrono::Local> = chrono::Local::now();
         let simple_dimming_schedule = WLEDSchedule::from([
             WLEDScheduleItem {
-                time: ScheduleTime::Time(NaiveTime::from_hms(7, 0, 0)),
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(7, 0, 0).unwrap()),
                 change: WLEDChange::Brightness(0.2),
+                ..Default::default()
             },
             WLEDScheduleItem {
-                time: ScheduleTime::Time(NaiveTime::from_hms(8, 0, 0)),
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(8, 0, 0).unwrap()),
                 change: WLEDChange::Brightness(0.8),
+                ..Default::default()
             },
             WLEDScheduleItem {
-                time: ScheduleTime::Time(NaiveTime::from_hms(19, 0, 0)),
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(19, 0, 0).unwrap()),
                 change: WLEDChange::Brightness(0.8),
+                ..Default::default()
             },
             WLEDScheduleItem {
-                time: ScheduleTime::Time(NaiveTime::from_hms(20, 0, 0)),
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(20, 0, 0).unwrap()),
                 change: WLEDChange::Brightness(0.2),
+                ..Default::default()
             },
         ]);
 
-        let dim_pc = calc_led_state_scheduled(today, 49., -124., &simple_dimming_schedule);
-        println!("DIM PC actual: {:?}", dim_pc);
+        let LEDState {
+            bri, preset, power, ..
+        } = calc_led_state_scheduled(today, 49., -124., &simple_dimming_schedule);
+        assert!((0.2..=0.8).contains(&bri));
+        assert_eq!(preset, None);
+        assert_eq!(power, None);
 
-        let dim_pc = calc_led_state_scheduled(
-            today.with_time(NaiveTime::from_hms(19, 30, 0)).unwrap(),
+        let bri = calc_led_state_scheduled(
+            today
+                .with_time(NaiveTime::from_hms_opt(19, 30, 0).unwrap())
+                .unwrap(),
             49.,
             -124.,
             &simple_dimming_schedule,
-        );
+        )
+        .bri;
+        assert!((bri - 0.5).abs() < 0.001, "7:30PM was {}", bri);
 
-        println!("DIM PC at 7:30PM: {:?}", dim_pc);
-        let dim_pc = calc_led_state_scheduled(
-            today.with_time(NaiveTime::from_hms(7, 30, 0)).unwrap(),
+        let bri = calc_led_state_scheduled(
+            today
+                .with_time(NaiveTime::from_hms_opt(7, 30, 0).unwrap())
+                .unwrap(),
             49.,
             -124.,
             &simple_dimming_schedule,
-        );
-        println!("DIM PC at 7:30AM: {:?}", dim_pc);
+        )
+        .bri;
+        assert!((bri - 0.5).abs() < 0.001, "7:30AM was {}", bri);
 
-        let dim_pc = calc_led_state_scheduled(
-            today.with_time(NaiveTime::from_hms(0, 0, 0)).unwrap(),
+        let bri = calc_led_state_scheduled(
+            today
+                .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+                .unwrap(),
             49.,
             -124.,
             &simple_dimming_schedule,
-        );
-        println!("DIM PC at midnight: {:?}", dim_pc);
+        )
+        .bri;
+        assert!((bri - 0.2).abs() < 0.001, "midnight was {}", bri);
+
+        let bri = calc_led_state_scheduled(
+            today
+                .with_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap())
+                .unwrap(),
+            49.,
+            -124.,
+            &simple_dimming_schedule,
+        )
+        .bri;
+        assert!((bri - 0.8).abs() < 0.001, "noon was {}", bri);
+    }
+
+    #[test]
+    fn test_interpolated_schedule() {
+        let today: chrono::DateTime<chrono::Local> = chrono::Local::now();
+        let keyframe = |h: u32, bri: f32, interpolation: Interpolation| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap()),
+            change: WLEDChange::Brightness(bri),
+            interpolation,
+            ..Default::default()
+        };
+        let schedule = WLEDSchedule::from([
+            keyframe(6, 0.0, Interpolation::Step),
+            keyframe(8, 1.0, Interpolation::EaseInOut),
+            keyframe(12, 0.5, Interpolation::Exponential),
+            keyframe(16, 0.125, Interpolation::EaseIn),
+            keyframe(18, 1.0, Interpolation::EaseOut),
+            keyframe(20, 0.0, Interpolation::Linear),
+        ]);
+        let at = |h: u32, m: u32| {
+            calc_led_state_scheduled(
+                today
+                    .with_time(NaiveTime::from_hms_opt(h, m, 0).unwrap())
+                    .unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+            .bri
+        };
+
+        // Step holds until the next keyframe and then snaps.
+        assert_eq!(at(7, 59), 0.0);
+        assert_eq!(at(8, 0), 1.0);
+        // Ease in/out is symmetric around the midpoint, and slow at the ends.
+        assert!((at(10, 0) - 0.75).abs() < 0.001);
+        assert!(at(8, 30) > 0.95);
+        // Exponential halves every two hours from 0.5 down to 0.125.
+        assert!((at(14, 0) - 0.25).abs() < 0.001);
+        // Ease in lags behind linear (0.5625 here), ease out leads it (0.5).
+        assert!(at(17, 0) < 0.5625);
+        assert!(at(19, 0) < 0.5);
+        // Linear wraps overnight back to the 6AM step.
+        assert!((at(1, 0) - 0.0).abs() < 0.001);
+        for (interpolation, from, to) in [
+            (Interpolation::Step, 0.2, 0.8),
+            (Interpolation::Linear, 0.2, 0.8),
+            (Interpolation::EaseIn, 0.8, 0.2),
+            (Interpolation::EaseOut, 0.2, 0.8),
+            (Interpolation::EaseInOut, 0.8, 0.2),
+            (Interpolation::Exponential, 0.0, 0.8),
+        ] {
+            assert!((interpolation.interpolate(from, to, 1.) - to).abs() < 0.001);
+            assert!((interpolation.interpolate(from, to, 0.) - from).abs() < 0.005);
+        }
+    }
+
+    #[test]
+    fn test_day_type_schedule() {
+        use chrono::TimeZone;
+        let keyframe = |h: u32, m: u32, bri: f32, days: ActiveDays| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, m, 0).unwrap()),
+            change: WLEDChange::Brightness(bri),
+            days,
+            ..Default::default()
+        };
+        let schedule = WLEDSchedule::from([
+            keyframe(6, 0, 0.2, ActiveDays::Weekdays),
+            keyframe(7, 0, 1.0, ActiveDays::Weekdays),
+            keyframe(22, 0, 0.2, ActiveDays::Weekdays),
+            keyframe(9, 0, 0.6, ActiveDays::Weekends),
+            keyframe(10, 0, 1.0, ActiveDays::Weekends),
+            keyframe(23, 0, 0.6, ActiveDays::Weekends),
+        ]);
+        // 2024-06-07 was a Friday.
+        let at = |d: u32, h: u32, m: u32| {
+            calc_led_state_scheduled(
+                chrono::Local.with_ymd_and_hms(2024, 6, d, h, m, 0).unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+            .bri
+        };
+
+        assert!((at(7, 6, 30) - 0.6).abs() < 0.001);
+        // Friday 22:00 (0.2) blends into Saturday 09:00 (0.6) overnight.
+        assert!((at(8, 3, 30) - 0.4).abs() < 0.001);
+        // No weekday morning ramp on Saturday.
+        assert!((at(8, 6, 30) - 0.5091).abs() < 0.001);
+        assert!((at(8, 10, 0) - 1.0).abs() < 0.001);
+        // Sunday 23:00 (0.6) blends into Monday 06:00 (0.2).
+        assert!((at(10, 2, 30) - 0.4).abs() < 0.001);
+        assert!((at(10, 6, 30) - 0.6).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_uncovered_schedule() {
+        use chrono::TimeZone;
+        let item = |h: u32, change: WLEDChange| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap()),
+            change,
+            dates: Some(DateRange {
+                from: (12, 1),
+                until: (1, 6),
+            }),
+            ..Default::default()
+        };
+        let schedule = WLEDSchedule::from([
+            item(8, WLEDChange::Brightness(1.0)),
+            item(20, WLEDChange::Brightness(0.0)),
+        ]);
+        let at = |mo: u32, d: u32| {
+            let now = chrono::Local
+                .with_ymd_and_hms(2024, mo, d, 12, 0, 0)
+                .unwrap();
+            ResolvedSchedule::new(&schedule, now.date_naive(), &chrono::Local, 49., -124.)
+                .state_at(now.timestamp())
+        };
+        assert!(at(12, 25).is_some());
+        // Nothing to blend between outside the dates, rather than a dark light.
+        assert_eq!(at(6, 7), None);
+    }
+
+    #[test]
+    fn test_date_range_schedule() {
+        use chrono::TimeZone;
+        let schedule = WLEDSchedule::from([
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(0, 0, 0).unwrap()),
+                change: WLEDChange::Brightness(0.5),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(0, 0, 0).unwrap()),
+                change: WLEDChange::Preset(1),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(17, 0, 0).unwrap()),
+                change: WLEDChange::Preset(5),
+                dates: Some(DateRange {
+                    from: (12, 1),
+                    until: (1, 6),
+                }),
+                ..Default::default()
+            },
+        ]);
+        let at = |y: i32, mo: u32, d: u32, h: u32| {
+            calc_led_state_scheduled(
+                chrono::Local.with_ymd_and_hms(y, mo, d, h, 0, 0).unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+            .preset
+        };
+
+        assert_eq!(at(2024, 12, 25, 18), Some(5));
+        assert_eq!(at(2024, 12, 26, 1), Some(1));
+        assert_eq!(at(2025, 1, 6, 18), Some(5));
+        assert_eq!(at(2025, 1, 7, 18), Some(1));
+        assert_eq!(at(2024, 11, 30, 18), Some(1));
+        assert_eq!(at(2024, 6, 7, 18), Some(1));
+    }
+
+    #[test]
+    fn test_dst_spring_forward_schedule() {
+        use chrono::TimeZone;
+        use chrono_tz::America::Vancouver;
+        let schedule = WLEDSchedule::from([
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(7, 0, 0).unwrap()),
+                change: WLEDChange::Brightness(1.0),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(22, 0, 0).unwrap()),
+                change: WLEDChange::Brightness(0.0),
+                ..Default::default()
+            },
+            // Doesn't exist on 2024-03-10; lands at 03:30 PDT instead.
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(2, 30, 0).unwrap()),
+                change: WLEDChange::Power(true),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(23, 0, 0).unwrap()),
+                change: WLEDChange::Power(false),
+                ..Default::default()
+            },
+        ]);
+        let at = |d: u32, h: u32, m: u32| {
+            calc_led_state_scheduled(
+                Vancouver.with_ymd_and_hms(2024, 3, d, h, m, 0).unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+        };
+
+        // 22:00 PST to 07:00 PDT is only 8 real hours, so 03:00 PDT (four
+        // hours in) is halfway.
+        assert!((at(10, 3, 0).bri - 0.5).abs() < 0.001);
+        assert_eq!(at(10, 3, 29).power, Some(false));
+        assert_eq!(at(10, 3, 31).power, Some(true));
+        // The night before is a normal 9 hour night.
+        assert!((at(9, 2, 30).bri - 0.5).abs() < 0.001);
+        // Tomorrow's keyframes are resolved against tomorrow, not today + 24h.
+        assert!((at(9, 23, 0).bri - 0.125).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_dst_fall_back_schedule() {
+        use chrono::TimeZone;
+        use chrono_tz::America::Vancouver;
+        let schedule = WLEDSchedule::from([
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(0, 0, 0).unwrap()),
+                change: WLEDChange::Brightness(0.0),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(4, 0, 0).unwrap()),
+                change: WLEDChange::Brightness(1.0),
+                ..Default::default()
+            },
+            // Happens twice on 2024-11-03; we take the first (PDT) one.
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(1, 30, 0).unwrap()),
+                change: WLEDChange::Preset(2),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(12, 0, 0).unwrap()),
+                change: WLEDChange::Preset(1),
+                ..Default::default()
+            },
+        ]);
+        let midnight = Vancouver.with_ymd_and_hms(2024, 11, 3, 0, 0, 0).unwrap();
+        let after = |minutes: i64| {
+            calc_led_state_scheduled(
+                midnight + chrono::Duration::minutes(minutes),
+                49.,
+                -124.,
+                &schedule,
+            )
+        };
+
+        // 00:00 PDT to 04:00 PST is five real hours.
+        assert!((after(150).bri - 0.5).abs() < 0.001);
+        assert_eq!(after(89).preset, Some(1));
+        assert_eq!(after(91).preset, Some(2));
+        // Still preset 2 during the repeated 01:30 PST.
+        assert_eq!(after(151).preset, Some(2));
+        assert!((after(300).bri - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_calc_power_and_preset_schedule() {
+        let today: chrono::DateTime<chrono::Local> = chrono::Local::now();
+        let schedule = WLEDSchedule::from([
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(6, 0, 0).unwrap()),
+                change: WLEDChange::Power(true),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(7, 0, 0).unwrap()),
+                change: WLEDChange::Brightness(0.0),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(9, 0, 0).unwrap()),
+                change: WLEDChange::Preset(2),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(19, 0, 0).unwrap()),
+                change: WLEDChange::Brightness(1.0),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(21, 0, 0).unwrap()),
+                change: WLEDChange::Preset(1),
+                ..Default::default()
+            },
+            WLEDScheduleItem {
+                time: ScheduleTime::Time(NaiveTime::from_hms_opt(23, 0, 0).unwrap()),
+                change: WLEDChange::Power(false),
+                ..Default::default()
+            },
+        ]);
+        let at = |h: u32, m: u32| {
+            calc_led_state_scheduled(
+                today
+                    .with_time(NaiveTime::from_hms_opt(h, m, 0).unwrap())
+                    .unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+        };
+
+        // Before the first power event of the day we're still off from last night.
+        let LEDState { preset, power, .. } = at(5, 0);
+        assert_eq!(power, Some(false));
+        assert_eq!(preset, Some(1));
+        let LEDState { preset, power, .. } = at(6, 30);
+        assert_eq!(power, Some(true));
+        assert_eq!(preset, Some(1));
+        let LEDState { preset, power, .. } = at(12, 0);
+        assert_eq!(power, Some(true));
+        assert_eq!(preset, Some(2));
+        let LEDState { preset, power, .. } = at(22, 0);
+        assert_eq!(power, Some(true));
+        assert_eq!(preset, Some(1));
+        let LEDState { preset, power, .. } = at(23, 30);
+        assert_eq!(power, Some(false));
+        assert_eq!(preset, Some(1));
     }
 
     #[test]
@@ -517,6 +1474,415 @@ mod test {
         info!("DIM PC at sunset: {}", dim_pc);
     }
 
+    #[test]
+    fn test_calc_led_bri_curves() {
+        let mut led_config = LEDBrightnessConfig {
+            schedule: LEDScheduleSpec::Default,
+            min_bri: 10,
+            max_bri: 210,
+            curve: BrightnessCurve::Linear,
+            ..Default::default()
+        };
+        assert_eq!(calc_led_bri(&led_config, 0.), 10);
+        assert_eq!(calc_led_bri(&led_config, 0.5), 110);
+        assert_eq!(calc_led_bri(&led_config, 1.), 210);
+        assert_eq!(calc_led_bri(&led_config, 1.5), 210);
+
+        led_config.curve = BrightnessCurve::Gamma(2.);
+        assert_eq!(calc_led_bri(&led_config, 0.), 10);
+        assert_eq!(calc_led_bri(&led_config, 0.5), 60);
+        assert_eq!(calc_led_bri(&led_config, 1.), 210);
+
+        led_config.curve = BrightnessCurve::Cie;
+        assert_eq!(calc_led_bri(&led_config, 0.), 10);
+        // L*=50 is ~18.4% luminance.
+        assert_eq!(calc_led_bri(&led_config, 0.5), 47);
+        assert_eq!(calc_led_bri(&led_config, 1.), 210);
+        // Curves should never run backwards.
+        let mut last = 0u8;
+        for step in 0..=100 {
+            let bri = calc_led_bri(&led_config, step as f32 / 100.);
+            assert!(bri >= last);
+            last = bri;
+        }
+    }
+
+    #[test]
+    fn test_color_schedule() {
+        let today: chrono::DateTime<chrono::Local> = chrono::Local::now();
+        let keyframe = |h: u32, change: WLEDChange| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap()),
+            change,
+            ..Default::default()
+        };
+        let schedule = WLEDSchedule::from([
+            keyframe(5, WLEDChange::Cct(2200)),
+            keyframe(6, WLEDChange::Brightness(1.0)),
+            keyframe(7, WLEDChange::Cct(6500)),
+            keyframe(8, WLEDChange::Color(vec![255, 255, 255])),
+            keyframe(18, WLEDChange::Brightness(0.2)),
+            keyframe(20, WLEDChange::Cct(2200)),
+            keyframe(20, WLEDChange::Color(vec![255, 100, 0, 200])),
+        ]);
+        let at = |h: u32, m: u32| {
+            calc_led_state_scheduled(
+                today
+                    .with_time(NaiveTime::from_hms_opt(h, m, 0).unwrap())
+                    .unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+        };
+
+        // Warm all night, cooling through the morning.
+        assert_eq!(at(3, 0).cct, Some(2200));
+        assert_eq!(at(13, 30).cct, Some(4350));
+        assert_eq!(at(20, 0).cct, Some(2200));
+        // RGB keyframes get a zero white channel once any keyframe has one.
+        assert_eq!(at(8, 0).color, Some(vec![255, 255, 255, 0]));
+        assert_eq!(at(14, 0).color, Some(vec![255, 178, 128, 100]));
+        assert_eq!(at(23, 0).color, Some(vec![255, 139, 64, 150]));
+        // None of this should upset brightness.
+        assert!((at(12, 0).bri - 0.6).abs() < 0.001);
+
+        let no_color = WLEDSchedule::from([
+            keyframe(6, WLEDChange::Brightness(1.0)),
+            keyframe(18, WLEDChange::Brightness(0.2)),
+        ]);
+        let state = calc_led_state_scheduled(today, 49., -124., &no_color);
+        assert_eq!(state.cct, None);
+        assert_eq!(state.color, None);
+    }
+
+    #[test]
+    fn test_effect_schedule() {
+        let today: chrono::DateTime<chrono::Local> = chrono::Local::now();
+        let keyframe = |h: u32, change: WLEDChange| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap()),
+            change,
+            ..Default::default()
+        };
+        let schedule = WLEDSchedule::from([
+            keyframe(8, WLEDChange::Brightness(1.0)),
+            keyframe(8, WLEDChange::Effect(NameOrId::Id(0))),
+            keyframe(8, WLEDChange::Speed(0)),
+            keyframe(18, WLEDChange::Brightness(0.2)),
+            keyframe(18, WLEDChange::Effect(NameOrId::Name("Candle".to_string()))),
+            keyframe(
+                18,
+                WLEDChange::Palette(NameOrId::Name("Sunset".to_string())),
+            ),
+            keyframe(18, WLEDChange::Speed(200)),
+            keyframe(18, WLEDChange::Intensity(64)),
+        ]);
+        let at = |h: u32| {
+            calc_led_state_scheduled(
+                today
+                    .with_time(NaiveTime::from_hms_opt(h, 0, 0).unwrap())
+                    .unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+        };
+
+        // Effects and palettes step, speed and intensity blend.
+        let state = at(13);
+        assert_eq!(state.effect, Some(NameOrId::Id(0)));
+        assert_eq!(state.palette, Some(NameOrId::Name("Sunset".to_string())));
+        assert_eq!(state.speed, Some(100));
+        assert_eq!(state.intensity, Some(64));
+        assert!(state.has_segment_changes());
+        let state = at(20);
+        assert_eq!(state.effect, Some(NameOrId::Name("Candle".to_string())));
+        assert_eq!(state.speed, Some(171));
+    }
+
+    #[test]
+    fn test_playlist_and_nightlight_schedule() {
+        let keyframe = |h: u32, m: u32, change: WLEDChange| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, m, 0).unwrap()),
+            change,
+            ..Default::default()
+        };
+        let schedule = WLEDSchedule::from([
+            keyframe(7, 0, WLEDChange::Brightness(1.0)),
+            keyframe(18, 0, WLEDChange::Playlist(5)),
+            keyframe(19, 30, WLEDChange::Brightness(0.5)),
+            keyframe(19, 30, WLEDChange::StopPlaylist),
+            keyframe(
+                19,
+                30,
+                WLEDChange::Nightlight {
+                    duration: 30,
+                    target_bri: 0,
+                    mode: NightlightMode::Fade,
+                },
+            ),
+        ]);
+        let at = |h: u32, m: u32| {
+            calc_led_state_scheduled(
+                Local.with_ymd_and_hms(2024, 6, 7, h, m, 0).unwrap(),
+                49.,
+                -124.,
+                &schedule,
+            )
+        };
+
+        let state = at(12, 0);
+        assert_eq!(state.playlist, Some(PlaylistState::Stopped));
+        assert_eq!(state.nightlight, None);
+        assert_eq!(at(18, 30).playlist, Some(PlaylistState::Running(5)));
+        let state = at(19, 45);
+        assert_eq!(state.playlist, Some(PlaylistState::Stopped));
+        let nightlight = state.nightlight.unwrap();
+        assert_eq!(nightlight.duration, 30);
+        assert_eq!(
+            nightlight.started as i64,
+            Local
+                .with_ymd_and_hms(2024, 6, 7, 19, 30, 0)
+                .unwrap()
+                .timestamp()
+        );
+        // Same nightlight every cycle, so it only gets sent once.
+        assert_eq!(at(19, 50).nightlight, Some(nightlight));
+        // And it's over after its duration, rather than restarting.
+        assert_eq!(at(20, 0).nightlight, None);
+        assert_eq!(at(6, 0).nightlight, None);
+    }
+
+    #[test]
+    fn test_manual_change() {
+        let led_config = LEDBrightnessConfig {
+            min_bri: 10,
+            max_bri: 110,
+            ..Default::default()
+        };
+        let last = LEDState {
+            bri: 0.5,
+            ..Default::default()
+        };
+        let current = |on: bool, bri: u8| State {
+            on: Some(on),
+            bri: Some(bri),
+            ..Default::default()
+        };
+        assert_eq!(manual_change(&led_config, &last, &current(true, 60)), None);
+        assert_eq!(
+            manual_change(&led_config, &last, &current(true, 200)),
+            Some("brightness is 200 instead of 60".to_string())
+        );
+        assert_eq!(
+            manual_change(&led_config, &last, &current(false, 60)),
+            Some("switched off".to_string())
+        );
+        // Everything else is only compared when the schedule set it.
+        let colored = LEDState {
+            preset: Some(3),
+            color: Some(vec![255, 80, 0]),
+            cct: Some(2700),
+            effect: Some(NameOrId::Name("Candle".to_string())),
+            speed: Some(128),
+            ..last.clone()
+        };
+        let seg = |col: Vec<u8>, cct: u16, sx: u8| State {
+            ps: Some(-1),
+            seg: Some(vec![Seg {
+                id: Some(0),
+                col: Some(vec![col]),
+                cct: Some(cct),
+                fx: Some(88),
+                sx: Some(sx),
+                ..Default::default()
+            }]),
+            ..current(true, 60)
+        };
+        assert_eq!(
+            manual_change(&led_config, &colored, &seg(vec![255, 80, 0, 0], 25, 128)),
+            None
+        );
+        assert_eq!(
+            manual_change(&led_config, &colored, &seg(vec![0, 0, 255], 25, 128)),
+            Some("color is [0, 0, 255] instead of [255, 80, 0]".to_string())
+        );
+        assert_eq!(
+            manual_change(&led_config, &colored, &seg(vec![255, 80, 0], 127, 128)),
+            Some("cct is 127 instead of 25".to_string())
+        );
+        assert_eq!(
+            manual_change(&led_config, &colored, &seg(vec![255, 80, 0], 25, 200)),
+            Some("speed is 200 instead of 128".to_string())
+        );
+        assert_eq!(
+            manual_change(
+                &led_config,
+                &colored,
+                &State {
+                    ps: Some(7),
+                    ..current(true, 60)
+                }
+            ),
+            Some("preset is 7 instead of 3".to_string())
+        );
+        // Segment settings belong to the segment schedules.
+        let segmented = LEDBrightnessConfig {
+            segments: HashMap::from([(
+                1,
+                SegmentConfig {
+                    schedule: LEDScheduleSpec::Default,
+                    min_bri: 0,
+                    max_bri: 255,
+                    curve: BrightnessCurve::Linear,
+                },
+            )]),
+            ..led_config.clone()
+        };
+        assert_eq!(
+            manual_change(&segmented, &colored, &seg(vec![0, 0, 255], 25, 128)),
+            None
+        );
+        // Scheduled off: brightness doesn't matter, only being switched back on.
+        let last = LEDState {
+            power: Some(false),
+            ..last
+        };
+        assert_eq!(
+            manual_change(&led_config, &last, &current(false, 200)),
+            None
+        );
+        assert_eq!(
+            manual_change(&led_config, &last, &current(true, 60)),
+            Some("switched on".to_string())
+        );
+    }
+
+    #[test]
+    fn test_next_keyframe_after() {
+        let keyframe = |h: u32, change: WLEDChange, days: ActiveDays| WLEDScheduleItem {
+            time: ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap()),
+            change,
+            days,
+            ..Default::default()
+        };
+        let schedule = WLEDSchedule::from([
+            keyframe(8, WLEDChange::Brightness(1.0), ActiveDays::Weekdays),
+            keyframe(10, WLEDChange::Brightness(1.0), ActiveDays::Weekends),
+            keyframe(20, WLEDChange::Brightness(0.2), ActiveDays::Daily),
+        ]);
+        // 2024-06-07 was a Friday.
+        let at = |d: u32, h: u32| Local.with_ymd_and_hms(2024, 6, d, h, 0, 0).unwrap();
+        let resolved = ResolvedSchedule::new(&schedule, at(7, 12).date_naive(), &Local, 49., -124.);
+        assert_eq!(
+            resolved.next_keyframe_after(at(7, 12).timestamp()),
+            Some(at(7, 20).timestamp() as u64)
+        );
+        // Friday night's next keyframe is Saturday's late start.
+        assert_eq!(
+            resolved.next_keyframe_after(at(7, 20).timestamp()),
+            Some(at(8, 10).timestamp() as u64)
+        );
+    }
+
+    #[test]
+    fn test_find_name() {
+        let effects: Option<wled_json_api_library::structures::effects::Effects> =
+            serde_json::from_str(r#"["Solid","Blink","Breathe","Candle"]"#).ok();
+        let names = name_list(&effects);
+        assert_eq!(names.len(), 4);
+        assert_eq!(
+            find_name(&names, &NameOrId::Name("candle".to_string())),
+            Some(3)
+        );
+        assert_eq!(
+            find_name(&names, &NameOrId::Name("Rainbow".to_string())),
+            None
+        );
+        // IDs are passed through without checking, as older firmware may not list them all.
+        assert_eq!(find_name(&names, &NameOrId::Id(42)), Some(42));
+        assert!(name_list::<Vec<String>>(&None).is_empty());
+    }
+
+    #[test]
+    fn test_calc_segments_scheduled() {
+        let time = |h| ScheduleTime::Time(NaiveTime::from_hms_opt(h, 0, 0).unwrap());
+        let mut config = Config::default();
+        // The shelf dims out at 20:00, while the worklight holds until 22:00.
+        config.schedule.insert(
+            "shelf".to_string(),
+            vec![
+                WLEDScheduleItem {
+                    time: time(8),
+                    change: WLEDChange::Brightness(1.0),
+                    ..Default::default()
+                },
+                WLEDScheduleItem {
+                    time: time(20),
+                    change: WLEDChange::Brightness(0.0),
+                    ..Default::default()
+                },
+            ],
+        );
+        config.schedule.insert(
+            "worklight".to_string(),
+            vec![
+                WLEDScheduleItem {
+                    time: time(8),
+                    change: WLEDChange::Brightness(1.0),
+                    ..Default::default()
+                },
+                WLEDScheduleItem {
+                    time: time(8),
+                    change: WLEDChange::Power(true),
+                    ..Default::default()
+                },
+                WLEDScheduleItem {
+                    time: time(22),
+                    change: WLEDChange::Brightness(1.0),
+                    ..Default::default()
+                },
+                WLEDScheduleItem {
+                    time: time(23),
+                    change: WLEDChange::Power(false),
+                    ..Default::default()
+                },
+                WLEDScheduleItem {
+                    time: time(23),
+                    change: WLEDChange::Brightness(0.0),
+                    ..Default::default()
+                },
+            ],
+        );
+        let segment = |name: &str, min_bri, max_bri| SegmentConfig {
+            schedule: LEDScheduleSpec::ByName(name.to_string()),
+            min_bri,
+            max_bri,
+            curve: BrightnessCurve::Linear,
+        };
+        let led_config = LEDBrightnessConfig {
+            schedule: LEDScheduleSpec::Default,
+            segments: HashMap::from([
+                (2, segment("shelf", 0, 100)),
+                (0, segment("worklight", 50, 250)),
+                (1, segment("missing", 0, 255)),
+            ]),
+            ..Default::default()
+        };
+        let now = Local.with_ymd_and_hms(2024, 6, 7, 20, 0, 0).unwrap();
+        let schedules = Schedules::new(&config, now);
+        let segments = calc_segments_scheduled(&schedules, &led_config, now.timestamp());
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].0, 0);
+        assert_eq!(segments[0].1, 250);
+        assert_eq!(segments[0].2.power, Some(true));
+        assert_eq!(segments[1].0, 2);
+        assert_eq!(segments[1].1, 0);
+
+        let now = Local.with_ymd_and_hms(2024, 6, 7, 23, 30, 0).unwrap();
+        let segments = calc_segments_scheduled(&schedules, &led_config, now.timestamp());
+        assert_eq!(segments[0].2.power, Some(false));
+    }
+
     #[test]
     fn test_bri_calc() {
         let high = 50u8;
@@ -526,4 +1892,95 @@ mod test {
         let new_bri = (high as f32 - (dim_pc * gap)).min(255.).max(0.) as u8;
         info!("New bri is {}", new_bri);
     }
+
+    #[test]
+    fn test_register_static_devices() {
+        let mut config = Config {
+            http_connect_timeout_ms: 200,
+            http_timeout_ms: 200,
+            ..Default::default()
+        };
+        // Nothing answers on port 1, but the device is still registered.
+        config.devices.insert(
+            "wled-vlan".to_string(),
+            StaticDevice {
+                host: "127.0.0.1".to_string(),
+                port: 1,
+            },
+        );
+        config.devices.insert(
+            "wled-nowhere".to_string(),
+            StaticDevice {
+                host: "wled-nowhere.invalid".to_string(),
+                port: 80,
+            },
+        );
+        let client = http_client(&config).unwrap();
+        let mut found_wled = HashMap::new();
+        register_static_devices(&config, &mut found_wled, &client);
+        assert_eq!(found_wled.len(), 1);
+        let wled = &found_wled["wled-vlan"];
+        assert_eq!(wled.address, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(wled.device.url.as_str(), "http://127.0.0.1:1/");
+        assert!(wled.state.is_none());
+
+        let info = ServiceInfo::new(
+            "_wled._tcp.local.",
+            "wled-other",
+            "wled-other.local.",
+            "127.0.0.1",
+            80,
+            None,
+        )
+        .unwrap();
+        assert!(is_static_device(&config, &found_wled, &info));
+        let info = ServiceInfo::new(
+            "_wled._tcp.local.",
+            "wled-other",
+            "wled-other.local.",
+            "127.0.0.2",
+            80,
+            None,
+        )
+        .unwrap();
+        assert!(!is_static_device(&config, &found_wled, &info));
+    }
+
+    #[test]
+    fn test_sort_addresses() {
+        let addresses: Vec<IpAddr> = ["fe80::1", "192.168.1.20", "192.168.1.3"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        let sorted = sort_addresses(&addresses, AddressFamily::V4);
+        assert_eq!(sorted[0].to_string(), "192.168.1.3");
+        assert_eq!(sorted[2].to_string(), "fe80::1");
+        let sorted = sort_addresses(&addresses, AddressFamily::V6);
+        assert_eq!(sorted[0].to_string(), "fe80::1");
+        assert_eq!(
+            wled_url(sorted[0], 80).unwrap().as_str(),
+            "http://[fe80::1]/"
+        );
+        assert_eq!(
+            wled_url(sorted[1], 8080).unwrap().as_str(),
+            "http://192.168.1.3:8080/"
+        );
+    }
+
+    #[test]
+    fn test_restorable_state() {
+        let snapshot: State = serde_json::from_str(
+            r#"{"on": true, "bri": 90, "ps": 4, "pl": 2, "live": true, "lor": 2, "mainseg": 1,
+                "seg": [{"id": 0, "fx": 9, "col": [[255, 0, 0]]}, {"id": 1, "pal": 6}]}"#,
+        )
+        .unwrap();
+        let state = restorable_state(&snapshot);
+        assert_eq!(
+            (state.on, state.bri, state.mainseg),
+            (Some(true), Some(90), Some(1))
+        );
+        assert_eq!((state.ps, state.pl), (None, None));
+        assert_eq!((state.live, state.lor), (Some(false), Some(0)));
+        assert_eq!(state.seg, snapshot.seg);
+    }
 }